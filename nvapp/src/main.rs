@@ -13,9 +13,10 @@ mod utils;
 
 use maxwell::compute::*;
 use maxwell::dma::*;
-use utils::{align_up, GpuAllocated, GpuBox};
+use nix::errno::Errno;
+use utils::{align_up_checked, GpuAllocated, GpuBox};
 
-use nvgpu::GpuCharacteristics;
+use nvgpu::{Arch, GpuCharacteristics};
 
 const PROGRAM_REGION_ALIGNMENT: usize = 0x1000000;
 const SCRATCH_MEMORY_ALIGNMENT: usize = 0x20000;
@@ -23,25 +24,30 @@ const DEFAULT_SCRATCH_MEMORY_PER_SM: usize = 0x800;
 // TODO: define bindless texture constant buffer layout
 const BINDLESS_TEXTURE_CBUFF_INDEX: u32 = 0;
 
+/// Returns ``EINVAL`` if the size overflows.
 fn compute_total_scratch_size(
     gpu_characteristics: &GpuCharacteristics,
     wrap_scratch_size: u32,
-) -> u32 {
-    align_up(
-        wrap_scratch_size
-            * gpu_characteristics.sm_arch_warp_count
-            * gpu_characteristics.num_gpc
-            * gpu_characteristics.num_tpc_per_gpc,
-        SCRATCH_MEMORY_ALIGNMENT as u32,
-    )
+) -> NvGpuResult<u32> {
+    wrap_scratch_size
+        .checked_mul(gpu_characteristics.sm_arch_warp_count)
+        .and_then(|size| size.checked_mul(gpu_characteristics.num_gpc))
+        .and_then(|size| size.checked_mul(gpu_characteristics.num_tpc_per_gpc))
+        .and_then(|size| align_up_checked(size, SCRATCH_MEMORY_ALIGNMENT as u32))
+        .ok_or(Errno::EINVAL)
 }
 
 fn main() -> NvGpuResult<()> {
-    let (gpu_channel, gpu_characteristics) = utils::initialize().unwrap();
+    let gpu_context = utils::initialize()?;
+    let gpu_characteristics = gpu_context.characteristics();
 
-    assert_eq!(gpu_characteristics.chip_name(), "gm20b");
+    if let Err(error) = gpu_characteristics.require_arch(&[Arch::Maxwell]) {
+        eprintln!("{}, only Maxwell is supported", error);
 
-    let mut command_stream = utils::initialize_command_stream(&gpu_channel)?;
+        return Err(error.into());
+    }
+
+    let mut command_stream = utils::initialize_command_stream(&gpu_context)?;
 
     println!("{:?}", gpu_characteristics);
     println!(
@@ -50,9 +56,11 @@ fn main() -> NvGpuResult<()> {
     );
 
     // TODO: fancy address space allocation (one day)
-    let program_region = GpuBox::new_with_alignment([0xAAAAAAAAu64; 1], PROGRAM_REGION_ALIGNMENT);
+    let program_region =
+        GpuBox::new_with_alignment(&gpu_context, [0xAAAAAAAAu64; 1], PROGRAM_REGION_ALIGNMENT);
     let scratch_memory = GpuAllocated::new(
-        compute_total_scratch_size(&gpu_characteristics, DEFAULT_SCRATCH_MEMORY_PER_SM as u32)
+        &gpu_context,
+        compute_total_scratch_size(gpu_characteristics, DEFAULT_SCRATCH_MEMORY_PER_SM as u32)?
             as usize,
         SCRATCH_MEMORY_ALIGNMENT,
     )?;
@@ -65,8 +73,8 @@ fn main() -> NvGpuResult<()> {
         gpu_characteristics.sm_arch_spa_version,
     )?;
 
-    let src_res_buffer = GpuBox::new([0xCAFEu64; 0x2]);
-    let copy_res_buffer = GpuBox::new([0x0u64; 0x2]);
+    let src_res_buffer = GpuBox::new_array(&gpu_context, 0x2, 0xCAFEu64)?;
+    let copy_res_buffer = GpuBox::new_array(&gpu_context, 0x2, 0x0u64)?;
 
     memcpy_1d(
         &mut command_stream,
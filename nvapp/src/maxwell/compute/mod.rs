@@ -1,8 +1,12 @@
 use super::common::ReductionOperation;
-use crate::utils::{Command, CommandStream, CommandSubmissionMode, GpuAllocated, SubChannelId};
+use crate::utils::{
+    Command, CommandStream, CommandSubmissionMode, GpuAllocated, GpuBox, SubChannelId,
+};
 use bitfield::BitRange;
 use core::convert::TryInto;
-use nvgpu::{GpuVirtualAddress, NvGpuResult};
+use nix::errno::Errno;
+use nvgpu::{GpuContext, GpuVirtualAddress, NvGpuResult, TpcMasks};
+use std::sync::Arc;
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum DependentQmdType {
@@ -393,9 +397,54 @@ impl BitRange<u8> for QueueMetaData17Release {
     }
 }
 
+/// Errors that can happen while building a QMD.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum QmdError {
+    /// The given release or constant buffer index is out of range.
+    InvalidIndex(usize),
+}
+
+impl From<QmdError> for Errno {
+    fn from(_: QmdError) -> Errno {
+        Errno::EINVAL
+    }
+}
+
 #[repr(C)]
 pub struct QueueMetaData17(pub [u32; 0x40]);
 
+// The hardware expects a QMD to be exactly 0x100 bytes.
+const _: () = assert!(core::mem::size_of::<QueueMetaData17>() == 0x100);
+
+/// The alignment required by the hardware for a QMD.
+pub const QMD_ALIGNMENT: usize = 0x100;
+
+/// The QMD major version used by Maxwell.
+pub const QMD_MAJOR_VERSION: u32 = 1;
+
+/// The QMD version used by Maxwell.
+pub const QMD_VERSION: u32 = 7;
+
+/// The maximum register count that can be set in a QMD.
+pub const QMD_MAX_REGISTER_COUNT: u32 = 0xFF;
+
+/// The maximum shared memory size that can be set in a QMD.
+pub const QMD_MAX_SHARED_MEMORY_SIZE: u32 = 0x3FF00;
+
+/// The granularity of the shared memory size in a QMD.
+pub const QMD_SHARED_MEMORY_ALIGNMENT: u32 = 0x100;
+
+/// Allocate a zero-initialized QMD in GPU memory with the required alignment.
+pub fn alloc_qmd(context: &Arc<GpuContext>) -> NvGpuResult<GpuBox<QueueMetaData17>> {
+    GpuBox::try_new_with_alignment(context, QueueMetaData17::default(), QMD_ALIGNMENT)
+}
+
+impl Default for QueueMetaData17 {
+    fn default() -> Self {
+        QueueMetaData17([0; 0x40])
+    }
+}
+
 impl QueueMetaData17 {
     bitfield_fields! {
         #[inline]
@@ -699,13 +748,85 @@ impl QueueMetaData17 {
 }
 
 impl QueueMetaData17 {
+    /// Create a new QMD with the Maxwell version, all caches invalidated and no call limit.
+    pub fn new() -> Self {
+        let mut qmd = QueueMetaData17::default();
+
+        qmd.set_qmd_major_version(QMD_MAJOR_VERSION);
+        qmd.set_qmd_version(QMD_VERSION);
+        qmd.set_invalidate_texture_header_cache(true);
+        qmd.set_invalidate_texture_sampler_cache(true);
+        qmd.set_invalidate_texture_data_cache(true);
+        qmd.set_invalidate_shader_data_cache(true);
+        qmd.set_invalidate_instruction_cache(true);
+        qmd.set_invalidate_shader_constant_cache(true);
+        qmd.set_api_visible_call_limit(ApiVisibleCallLimit::NoCheck);
+
+        qmd
+    }
+
+    /// Set the program offset relative to the program region.
+    pub fn with_program(mut self, offset: u32) -> Self {
+        self.set_program_offset(offset);
+
+        self
+    }
+
+    /// Set the shared memory size.
+    ///
+    /// Returns ``EINVAL`` if the size isn't a multiple of 0x100 or is too big.
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn with_shared_memory(mut self, size: u32) -> NvGpuResult<Self> {
+        if size % QMD_SHARED_MEMORY_ALIGNMENT != 0 || size > QMD_MAX_SHARED_MEMORY_SIZE {
+            return Err(Errno::EINVAL);
+        }
+
+        self.set_shared_memory_size(size);
+
+        Ok(self)
+    }
+
+    /// Set the register count.
+    ///
+    /// Returns ``EINVAL`` if the count is greater than 255.
+    pub fn with_registers(mut self, count: u32) -> NvGpuResult<Self> {
+        if count > QMD_MAX_REGISTER_COUNT {
+            return Err(Errno::EINVAL);
+        }
+
+        self.set_register_count(count);
+
+        Ok(self)
+    }
+
     fn get_slice(&mut self, index: usize, size: usize) -> &mut [u32] {
         &mut self.0[index..index + size]
     }
 
-    pub fn set_release(&mut self, index: usize, value: &QueueMetaData17Release) {
+    /// Disable the given SMs for this dispatch.
+    ///
+    /// Returns ``EINVAL`` if the mask contains SMs that are not available (floorswept ones included) or disables all of them.
+    pub fn disable_sms(&mut self, mask: u64, tpc_masks: &TpcMasks) -> NvGpuResult<()> {
+        let available = tpc_masks.available_sm_mask();
+
+        if mask & !available != 0 || (available != 0 && mask == available) {
+            return Err(Errno::EINVAL);
+        }
+
+        self.set_sm_disable_mask_lower(mask as u32);
+        self.set_sm_disable_mask_upper((mask >> 32) as u32);
+
+        Ok(())
+    }
+
+    /// Set the release at the given index (0 or 1).
+    pub fn set_release(
+        &mut self,
+        index: usize,
+        value: &QueueMetaData17Release,
+    ) -> Result<(), QmdError> {
         if index > 1 {
-            panic!("Invalid relase index {}", index);
+            return Err(QmdError::InvalidIndex(index));
         }
 
         let struc_size =
@@ -715,11 +836,20 @@ impl QueueMetaData17 {
             &mut self.0[0x17 + (index * struc_size)..0x17 + ((index + 1) * struc_size)];
 
         output_slice.copy_from_slice(&value.0[..]);
+
+        Ok(())
     }
 
-    pub fn set_constant_buffer(&mut self, index: usize, value: &QueueMetaData17ConstantBuffer) {
+    /// Set the constant buffer at the given index (0 to 7).
+    ///
+    /// NOTE: This doesn't mark the constant buffer as valid, see ``set_constant_buffer_valid``.
+    pub fn set_constant_buffer(
+        &mut self,
+        index: usize,
+        value: &QueueMetaData17ConstantBuffer,
+    ) -> Result<(), QmdError> {
         if index > 7 {
-            panic!("Invalid constant buffer index {}", index);
+            return Err(QmdError::InvalidIndex(index));
         }
 
         let struc_size =
@@ -728,10 +858,13 @@ impl QueueMetaData17 {
         let output_slice =
             &mut self.0[0x1D + (index * struc_size)..0x1D + ((index + 1) * struc_size)];
 
+        // The first word is the lower address, the second one holds the upper address, invalidate bit and size.
         let bytes = value.0.to_le_bytes();
 
         output_slice[0] = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
-        output_slice[1] = u32::from_le_bytes(bytes[4..8].try_into().unwrap())
+        output_slice[1] = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+
+        Ok(())
     }
 }
 
@@ -824,6 +957,10 @@ pub fn memcpy_inline_host_to_device(
     dst: GpuVirtualAddress,
     data: &[u8],
 ) -> NvGpuResult<()> {
+    if data.is_empty() {
+        return Err(Errno::EINVAL);
+    }
+
     // Setup dst and size.
     let mut setup_dst = Command::new(
         0x60,
@@ -837,26 +974,20 @@ pub fn memcpy_inline_host_to_device(
 
     command_stream.push(setup_dst)?;
 
+    // Launch the DMA and send the inline data right after it.
     let mut launch_dma_command = Command::new(
         0x6C,
         SubChannelId::Compute,
-        CommandSubmissionMode::Increasing,
+        CommandSubmissionMode::IncreasingOnce,
     );
 
     // TODO: map to bitfield
     launch_dma_command.push_argument(0x11);
 
-    command_stream.push(launch_dma_command)?;
-
     // Finally send inline data
-    let mut inline_data = Command::new(
-        0x6D,
-        SubChannelId::Compute,
-        CommandSubmissionMode::NonIncreasing,
-    );
-    inline_data.push_inlined_buffer(data);
+    launch_dma_command.push_inlined_buffer(data);
 
-    command_stream.push(inline_data)?;
+    command_stream.push(launch_dma_command)?;
 
     Ok(())
 }
@@ -943,3 +1074,195 @@ pub fn init_compute_engine_clean_state(
 
     Ok(())
 }
+
+/// Upload a copy of the given QMD with the given grid and block dimensions and launch it.
+///
+/// The QMD is uploaded with an inline-to-memory copy, the returned allocation must be kept alive until the dispatch is done.
+///
+/// Returns ``EINVAL`` if any dimension is zero or doesn't fit in the QMD.
+pub fn dispatch_compute(
+    command_stream: &mut CommandStream,
+    qmd: &QueueMetaData17,
+    grid: (u32, u32, u32),
+    block: (u32, u32, u32),
+) -> NvGpuResult<GpuAllocated> {
+    let (grid_width, grid_height, grid_depth) = grid;
+    let (block_x, block_y, block_z) = block;
+
+    let is_valid_u16 = |value: u32| value != 0 && value <= u32::from(u16::MAX);
+
+    if grid_width == 0
+        || !is_valid_u16(grid_height)
+        || !is_valid_u16(grid_depth)
+        || !is_valid_u16(block_x)
+        || !is_valid_u16(block_y)
+        || !is_valid_u16(block_z)
+    {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut qmd = QueueMetaData17(qmd.0);
+
+    qmd.set_cta_raster_width(grid_width);
+    qmd.set_cta_raster_height(grid_height);
+    qmd.set_cta_raster_depth(grid_depth);
+    qmd.set_cta_thread_dimension0(block_x);
+    qmd.set_cta_thread_dimension1(block_y);
+    qmd.set_cta_thread_dimension2(block_z);
+
+    let qmd_memory = GpuAllocated::new(
+        command_stream.context(),
+        core::mem::size_of::<QueueMetaData17>(),
+        QMD_ALIGNMENT,
+    )?;
+
+    let qmd_bytes: Vec<u8> = qmd.0.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+    memcpy_inline_host_to_device(command_stream, qmd_memory.gpu_address(), &qmd_bytes)?;
+
+    // Send PCAS A with the QMD address.
+    let mut send_pcas_a = Command::new(
+        0xAD,
+        SubChannelId::Compute,
+        CommandSubmissionMode::Increasing,
+    );
+    send_pcas_a.push_argument((qmd_memory.gpu_address() >> 8) as u32);
+    command_stream.push(send_pcas_a)?;
+
+    // Send signaling PCAS B, invalidate and schedule.
+    command_stream.push(Command::new_inline(0xAF, SubChannelId::Compute, 0x3))?;
+
+    Ok(qmd_memory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::initialize;
+    use nvgpu::{ChannelConfig, ClassId};
+
+    /// A program doing nothing but exiting: a scheduling word followed by ``EXIT``, ``BRA`` to itself and ``NOP``.
+    const EXIT_PROGRAM: [u64; 4] = [
+        0x001F_8000_FC00_07E0,
+        0xE300_0000_0007_000F,
+        0xE240_0FFF_FF87_000F,
+        0x50B0_0000_0007_0F00,
+    ];
+
+    #[test]
+    fn disable_sms_rejects_floorswept_sms() {
+        // The second TPC of the second GPC is floorswept.
+        let tpc_masks = TpcMasks::new(vec![0b11, 0b01], 2);
+        let mut qmd = QueueMetaData17::default();
+
+        assert_eq!(qmd.disable_sms(0b1000, &tpc_masks), Err(Errno::EINVAL));
+        assert_eq!(qmd.disable_sms(0b0111, &tpc_masks), Err(Errno::EINVAL));
+
+        qmd.disable_sms(0b0101, &tpc_masks).unwrap();
+
+        assert_eq!(qmd.sm_disable_mask_lower(), 0b0101);
+        assert_eq!(qmd.sm_disable_mask_upper(), 0);
+    }
+
+    #[test]
+    fn constant_buffer_round_trip() {
+        let mut qmd = QueueMetaData17::default();
+
+        for index in 0..8 {
+            let mut value = QueueMetaData17ConstantBuffer(0);
+            value.set_address_lower(0xDEAD_B000 + index as u32);
+            value.set_address_upper(0x12);
+            value.set_invalidate(index % 2 == 0);
+            value.set_size(0x100 * (index as u32 + 1));
+
+            qmd.set_constant_buffer(index, &value).unwrap();
+        }
+
+        for index in 0..8 {
+            let lower = u64::from(qmd.0[0x1D + index * 2]);
+            let upper = u64::from(qmd.0[0x1D + index * 2 + 1]);
+            let value = QueueMetaData17ConstantBuffer(upper << 32 | lower);
+
+            assert_eq!(value.address_lower(), 0xDEAD_B000 + index as u32);
+            assert_eq!(value.address_upper(), 0x12);
+            assert_eq!(value.invalidate(), index % 2 == 0);
+            assert_eq!(value.size(), 0x100 * (index as u32 + 1));
+        }
+    }
+
+    #[test]
+    fn out_of_range_indices_are_rejected() {
+        let mut qmd = QueueMetaData17::default();
+
+        assert_eq!(
+            qmd.set_release(2, &QueueMetaData17Release([0; 0x3])),
+            Err(QmdError::InvalidIndex(2))
+        );
+        assert_eq!(
+            qmd.set_constant_buffer(8, &QueueMetaData17ConstantBuffer(0)),
+            Err(QmdError::InvalidIndex(8))
+        );
+        assert_eq!(qmd.0, QueueMetaData17::default().0);
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn alloc_qmd_is_aligned() {
+        let context = initialize().unwrap();
+
+        // Keep every QMD alive so each one gets its own address.
+        let qmds: Vec<_> = (0..8).map(|_| alloc_qmd(&context).unwrap()).collect();
+
+        for qmd in &qmds {
+            assert_eq!(qmd.gpu_address() % QMD_ALIGNMENT as GpuVirtualAddress, 0);
+        }
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn dispatch_on_compute_only_channel() {
+        let context = Arc::new(GpuContext::open_with_config(&ChannelConfig::compute()).unwrap());
+        let mut command_stream = CommandStream::new(&context);
+
+        // Only the compute object context exists, bind it alone.
+        let mut bind_channel_command =
+            Command::new(0, SubChannelId::Compute, CommandSubmissionMode::Increasing);
+        bind_channel_command.push_argument(u32::from(ClassId::MAXWELL_B_COMPUTE));
+        command_stream.push(bind_channel_command).unwrap();
+
+        let program_region =
+            GpuBox::try_new_with_alignment(&context, EXIT_PROGRAM, 0x1000000).unwrap();
+        let scratch_memory = GpuAllocated::new(&context, 0x20000, 0x20000).unwrap();
+        let release_buffer = GpuBox::new(&context, 0u32);
+
+        init_compute_engine_clean_state(
+            &mut command_stream,
+            0,
+            program_region.gpu_address(),
+            &scratch_memory,
+            context.characteristics().sm_arch_spa_version,
+        )
+        .unwrap();
+
+        let mut release = QueueMetaData17Release([0; 0x3]);
+        release.set_address_lower(release_buffer.gpu_address() as u32);
+        release.set_address_upper((release_buffer.gpu_address() >> 32) as u32);
+        release.set_structure_size(StructureSize::OneWord);
+        release.set_payload(0xCAFE);
+
+        let mut qmd = QueueMetaData17::new()
+            .with_program(0)
+            .with_registers(8)
+            .unwrap();
+        qmd.set_release(0, &release).unwrap();
+        qmd.set_semaphore_release_enable0(true);
+
+        let _qmd_memory =
+            dispatch_compute(&mut command_stream, &qmd, (1, 1, 1), (1, 1, 1)).unwrap();
+
+        command_stream.flush().unwrap();
+        command_stream.wait_idle();
+
+        assert_eq!(*release_buffer, 0xCAFE);
+    }
+}
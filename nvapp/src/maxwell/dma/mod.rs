@@ -1,5 +1,6 @@
-use crate::utils::{Command, CommandStream, CommandSubmissionMode, SubChannelId};
-use nvgpu::{GpuVirtualAddress, NvGpuResult};
+use crate::utils::{Command, CommandStream, CommandSubmissionMode, GpuAllocated, SubChannelId};
+use nix::errno::Errno;
+use nvgpu::{GpuVirtualAddress, NvGpuResult, SurfaceKind};
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum DataTransferType {
@@ -279,12 +280,61 @@ impl LaunchDma {
     }
 }
 
+impl Default for LaunchDma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn memcpy_1d(
     command_stream: &mut CommandStream,
     dst: GpuVirtualAddress,
     src: GpuVirtualAddress,
     size: u32,
 ) -> NvGpuResult<()> {
+    memcpy_1d_internal(command_stream, dst, src, size, None)
+}
+
+/// Copy ``size`` bytes and release a semaphore (writing ``payload`` at ``semaphore_address``) once the copy is done.
+pub fn memcpy_1d_with_semaphore(
+    command_stream: &mut CommandStream,
+    dst: GpuVirtualAddress,
+    src: GpuVirtualAddress,
+    size: u32,
+    semaphore_address: GpuVirtualAddress,
+    payload: u32,
+) -> NvGpuResult<()> {
+    memcpy_1d_internal(
+        command_stream,
+        dst,
+        src,
+        size,
+        Some((semaphore_address, payload)),
+    )
+}
+
+fn memcpy_1d_internal(
+    command_stream: &mut CommandStream,
+    dst: GpuVirtualAddress,
+    src: GpuVirtualAddress,
+    size: u32,
+    semaphore: Option<(GpuVirtualAddress, u32)>,
+) -> NvGpuResult<()> {
+    if let Some((semaphore_address, payload)) = semaphore {
+        let mut setup_semaphore = Command::new(
+            0x90,
+            SubChannelId::DirectMemoryAccess,
+            CommandSubmissionMode::Increasing,
+        );
+
+        // SetSemaphoreA and SetSemaphoreB
+        setup_semaphore.push_address(semaphore_address);
+        // SetSemaphorePayload
+        setup_semaphore.push_argument(payload);
+
+        command_stream.push(setup_semaphore)?;
+    }
+
     // Setup lines to 1
     command_stream.push(Command::new_inline(
         0x107,
@@ -359,9 +409,439 @@ pub fn memcpy_1d(
     launch_dma.set_src_type(MemoryType::Virtual);
     launch_dma.set_dst_type(MemoryType::Virtual);
 
+    if semaphore.is_some() {
+        launch_dma.set_semaphore_type(SemaphoreType::ReleaseOneWord);
+    }
+
     launch_dma_command.push_argument(launch_dma.0);
 
     command_stream.push(launch_dma_command)?;
 
     Ok(())
 }
+
+/// Size of a block of a block linear surface, in GOBs.
+///
+/// NOTE: every dimension must be a power of two and blocks are always one GOB wide on Maxwell.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct BlockSize {
+    pub width_gobs: u32,
+    pub height_gobs: u32,
+    pub depth_gobs: u32,
+}
+
+impl BlockSize {
+    /// Encode the block size for the ``SetSrcBlockSize``/``SetDstBlockSize`` methods.
+    ///
+    /// Returns ``EINVAL`` if the block size isn't supported.
+    pub fn encode(&self) -> NvGpuResult<u32> {
+        let is_valid =
+            |gobs: u32, max_log2: u32| gobs.is_power_of_two() && gobs.trailing_zeros() <= max_log2;
+
+        if self.width_gobs != 1 || !is_valid(self.height_gobs, 5) || !is_valid(self.depth_gobs, 5) {
+            return Err(Errno::EINVAL);
+        }
+
+        // GOB height is 8 on Fermi and later.
+        let gob_height = 1;
+
+        Ok(self.height_gobs.trailing_zeros() << 4
+            | self.depth_gobs.trailing_zeros() << 8
+            | gob_height << 12)
+    }
+}
+
+/// Setup the block linear side of a copy, ``method`` being either ``SetSrcBlockSize`` or ``SetDstBlockSize``.
+fn push_block_linear_surface(
+    command_stream: &mut CommandStream,
+    method: u32,
+    block_size: BlockSize,
+    width_bytes: u32,
+    height: u32,
+) -> NvGpuResult<()> {
+    let mut setup_surface = Command::new(
+        method,
+        SubChannelId::DirectMemoryAccess,
+        CommandSubmissionMode::Increasing,
+    );
+
+    // BlockSize
+    setup_surface.push_argument(block_size.encode()?);
+    // Width
+    setup_surface.push_argument(width_bytes);
+    // Height
+    setup_surface.push_argument(height);
+    // Depth
+    setup_surface.push_argument(1);
+    // Layer
+    setup_surface.push_argument(0);
+    // Origin
+    setup_surface.push_argument(0);
+
+    command_stream.push(setup_surface)
+}
+
+/// Setup the addresses and lines of a multi line copy and launch it.
+#[allow(clippy::too_many_arguments)]
+fn push_multi_line_copy(
+    command_stream: &mut CommandStream,
+    dst: GpuVirtualAddress,
+    dst_pitch: u32,
+    dst_layout: MemoryLayout,
+    src: GpuVirtualAddress,
+    src_pitch: u32,
+    src_layout: MemoryLayout,
+    width_bytes: u32,
+    height: u32,
+) -> NvGpuResult<()> {
+    for command in multi_line_copy_commands(
+        dst,
+        dst_pitch,
+        dst_layout,
+        src,
+        src_pitch,
+        src_layout,
+        width_bytes,
+        height,
+    ) {
+        command_stream.push(command)?;
+    }
+
+    Ok(())
+}
+
+/// Build the commands of [push_multi_line_copy].
+///
+/// [push_multi_line_copy]: fn.push_multi_line_copy.html
+#[allow(clippy::too_many_arguments)]
+fn multi_line_copy_commands(
+    dst: GpuVirtualAddress,
+    dst_pitch: u32,
+    dst_layout: MemoryLayout,
+    src: GpuVirtualAddress,
+    src_pitch: u32,
+    src_layout: MemoryLayout,
+    width_bytes: u32,
+    height: u32,
+) -> Vec<Command> {
+    // Setup input and output address
+    let mut setup_io = Command::new(
+        0x100,
+        SubChannelId::DirectMemoryAccess,
+        CommandSubmissionMode::Increasing,
+    );
+
+    setup_io.push_address(src);
+    setup_io.push_address(dst);
+
+    let mut setup_lines = Command::new(
+        0x104,
+        SubChannelId::DirectMemoryAccess,
+        CommandSubmissionMode::Increasing,
+    );
+
+    // PitchIn
+    setup_lines.push_argument(src_pitch);
+    // PitchOut
+    setup_lines.push_argument(dst_pitch);
+    // LineLengthIn
+    setup_lines.push_argument(width_bytes);
+    // LineCount
+    setup_lines.push_argument(height);
+
+    let mut launch_dma_command = Command::new(
+        0xC0,
+        SubChannelId::DirectMemoryAccess,
+        CommandSubmissionMode::Increasing,
+    );
+
+    let mut launch_dma = LaunchDma::new();
+
+    launch_dma.set_data_transfer(DataTransferType::NonPipelined);
+    launch_dma.set_flush_enable(true);
+    launch_dma.set_src_memory_layout(src_layout);
+    launch_dma.set_dst_memory_layout(dst_layout);
+    launch_dma.set_multi_line_enable(true);
+    launch_dma.set_src_type(MemoryType::Virtual);
+    launch_dma.set_dst_type(MemoryType::Virtual);
+
+    launch_dma_command.push_argument(launch_dma.0);
+
+    vec![setup_io, setup_lines, launch_dma_command]
+}
+
+/// Copy a rectangle of ``width_bytes`` x ``height`` between two pitch linear surfaces.
+///
+/// Returns ``EINVAL`` if ``width_bytes`` is bigger than one of the pitches.
+pub fn memcpy_2d(
+    command_stream: &mut CommandStream,
+    dst: GpuVirtualAddress,
+    dst_pitch: u32,
+    src: GpuVirtualAddress,
+    src_pitch: u32,
+    width_bytes: u32,
+    height: u32,
+) -> NvGpuResult<()> {
+    if width_bytes > src_pitch || width_bytes > dst_pitch {
+        return Err(Errno::EINVAL);
+    }
+
+    push_multi_line_copy(
+        command_stream,
+        dst,
+        dst_pitch,
+        MemoryLayout::Pitch,
+        src,
+        src_pitch,
+        MemoryLayout::Pitch,
+        width_bytes,
+        height,
+    )
+}
+
+/// Copy a block linear surface of ``width_bytes`` x ``height`` to a pitch linear one.
+///
+/// Returns ``EINVAL`` if ``width_bytes`` is bigger than ``dst_pitch`` or if the block size isn't supported.
+pub fn copy_blocklinear_to_pitch(
+    command_stream: &mut CommandStream,
+    dst: GpuVirtualAddress,
+    dst_pitch: u32,
+    src: GpuVirtualAddress,
+    src_block_size: BlockSize,
+    width_bytes: u32,
+    height: u32,
+) -> NvGpuResult<()> {
+    if width_bytes > dst_pitch {
+        return Err(Errno::EINVAL);
+    }
+
+    // SetSrcBlockSize
+    push_block_linear_surface(command_stream, 0x1CA, src_block_size, width_bytes, height)?;
+
+    push_multi_line_copy(
+        command_stream,
+        dst,
+        dst_pitch,
+        MemoryLayout::Pitch,
+        src,
+        0,
+        MemoryLayout::BlockLinear,
+        width_bytes,
+        height,
+    )
+}
+
+/// Copy a pitch linear surface of ``width_bytes`` x ``height`` to a block linear one.
+///
+/// Returns ``EINVAL`` if ``width_bytes`` is bigger than ``src_pitch`` or if the block size isn't supported.
+pub fn copy_pitch_to_blocklinear(
+    command_stream: &mut CommandStream,
+    dst: GpuVirtualAddress,
+    dst_block_size: BlockSize,
+    src: GpuVirtualAddress,
+    src_pitch: u32,
+    width_bytes: u32,
+    height: u32,
+) -> NvGpuResult<()> {
+    if width_bytes > src_pitch {
+        return Err(Errno::EINVAL);
+    }
+
+    // SetDstBlockSize
+    push_block_linear_surface(command_stream, 0x1C3, dst_block_size, width_bytes, height)?;
+
+    push_multi_line_copy(
+        command_stream,
+        dst,
+        0,
+        MemoryLayout::BlockLinear,
+        src,
+        src_pitch,
+        MemoryLayout::Pitch,
+        width_bytes,
+        height,
+    )
+}
+
+/// Get the pitch and memory layout of one side of [copy_surface], checking that the rectangle fits in it.
+///
+/// [copy_surface]: fn.copy_surface.html
+fn surface_layout(
+    surface: &GpuAllocated,
+    pitch: u32,
+    width_bytes: u32,
+    height: u32,
+) -> NvGpuResult<(u32, MemoryLayout)> {
+    if SurfaceKind::from(surface.kind() as u8).is_block_linear() {
+        if u64::from(width_bytes) * u64::from(height) > surface.user_size() as u64 {
+            return Err(Errno::EINVAL);
+        }
+
+        return Ok((0, MemoryLayout::BlockLinear));
+    }
+
+    let end = u64::from(pitch) * u64::from(height.saturating_sub(1)) + u64::from(width_bytes);
+
+    if width_bytes > pitch || end > surface.user_size() as u64 {
+        return Err(Errno::EINVAL);
+    }
+
+    Ok((pitch, MemoryLayout::Pitch))
+}
+
+/// Copy a rectangle of ``width_bytes`` x ``height`` between two allocations, the memory layout of each side is deduced from its kind.
+///
+/// The pitches are used for the pitch linear sides and ``block_size`` for the block linear ones.
+///
+/// Returns ``EINVAL`` if the rectangle doesn't fit in one of the allocations or if the block size isn't supported.
+#[allow(clippy::too_many_arguments)]
+pub fn copy_surface(
+    command_stream: &mut CommandStream,
+    dst: &GpuAllocated,
+    dst_pitch: u32,
+    src: &GpuAllocated,
+    src_pitch: u32,
+    block_size: BlockSize,
+    width_bytes: u32,
+    height: u32,
+) -> NvGpuResult<()> {
+    let (dst_pitch, dst_layout) = surface_layout(dst, dst_pitch, width_bytes, height)?;
+    let (src_pitch, src_layout) = surface_layout(src, src_pitch, width_bytes, height)?;
+
+    // Nothing is pushed if the block size isn't supported.
+    block_size.encode()?;
+
+    if dst_layout == MemoryLayout::BlockLinear {
+        // SetDstBlockSize
+        push_block_linear_surface(command_stream, 0x1C3, block_size, width_bytes, height)?;
+    }
+
+    if src_layout == MemoryLayout::BlockLinear {
+        // SetSrcBlockSize
+        push_block_linear_surface(command_stream, 0x1CA, block_size, width_bytes, height)?;
+    }
+
+    push_multi_line_copy(
+        command_stream,
+        dst.gpu_address(),
+        dst_pitch,
+        dst_layout,
+        src.gpu_address(),
+        src_pitch,
+        src_layout,
+        width_bytes,
+        height,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{initialize, initialize_command_stream};
+    use nvgpu::{GpFifoEntry, KIND_GENERIC_16BX2};
+
+    #[test]
+    fn memcpy_2d_commands_decode() {
+        let words: Vec<u32> = multi_line_copy_commands(
+            0x2_0000_1000,
+            0x200,
+            MemoryLayout::Pitch,
+            0x3_0000_2000,
+            0x100,
+            MemoryLayout::Pitch,
+            0x80,
+            0x10,
+        )
+        .into_iter()
+        .flat_map(Command::into_vec)
+        .collect();
+
+        let expected = [
+            // OffsetIn and OffsetOut
+            (0x100, vec![0x3, 0x2000, 0x2, 0x1000]),
+            // PitchIn, PitchOut, LineLengthIn and LineCount
+            (0x104, vec![0x100, 0x200, 0x80, 0x10]),
+            // LaunchDma: non pipelined, flush, pitch to pitch, multi line
+            (0xC0, vec![0x386]),
+        ];
+
+        let mut position = 0;
+
+        for (method, arguments) in expected.iter() {
+            let entry = GpFifoEntry(words[position]);
+
+            assert_eq!(entry.method(), *method);
+            assert_eq!(entry.sub_channel(), 4);
+            assert!(!entry.is_inline());
+            assert_eq!(entry.effective_argument_count(), arguments.len());
+
+            position += 1;
+            assert_eq!(words[position..position + arguments.len()], arguments[..]);
+            position += arguments.len();
+        }
+
+        assert_eq!(position, words.len());
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn block_linear_round_trip() {
+        let context = initialize().unwrap();
+        let mut stream = initialize_command_stream(&context).unwrap();
+
+        let (pitch, height) = (0x100u32, 0x10u32);
+        let size = (pitch * height) as usize;
+        let block_size = BlockSize {
+            width_gobs: 1,
+            height_gobs: 2,
+            depth_gobs: 1,
+        };
+
+        let src = GpuAllocated::new(&context, size, 0x100).unwrap();
+        let block_linear =
+            GpuAllocated::new_with_kind(&context, size, 0x100, KIND_GENERIC_16BX2).unwrap();
+        let dst = GpuAllocated::new(&context, size, 0x100).unwrap();
+
+        assert_eq!(block_linear.kind(), KIND_GENERIC_16BX2);
+        assert_eq!(block_linear.gpu_address() % 0x10000, 0);
+        assert_eq!(block_linear.allocated_size() % 0x10000, 0);
+
+        let pattern: Vec<u8> = (0..size).map(|index| index as u8).collect();
+        src.map_array_mut::<u8>().unwrap().copy_from_slice(&pattern);
+        src.flush().unwrap();
+
+        copy_surface(
+            &mut stream,
+            &block_linear,
+            0,
+            &src,
+            pitch,
+            block_size,
+            pitch,
+            height,
+        )
+        .unwrap();
+        copy_surface(
+            &mut stream,
+            &dst,
+            pitch,
+            &block_linear,
+            0,
+            block_size,
+            pitch,
+            height,
+        )
+        .unwrap();
+        stream.flush().unwrap();
+        stream.wait_idle();
+
+        dst.invalidate().unwrap();
+        assert_eq!(dst.map_array::<u8>().unwrap(), &pattern[..]);
+    }
+
+    #[test]
+    fn launch_dma_default_is_zero() {
+        assert_eq!(LaunchDma::default().0, LaunchDma::new().0);
+        assert_eq!(LaunchDma::default().0, 0);
+    }
+}
@@ -1,7 +1,15 @@
 use super::common::ReductionOperation;
 use crate::utils::{Command, CommandStream, CommandSubmissionMode, SubChannelId};
+use nix::errno::Errno;
 use nvgpu::{GpuVirtualAddress, NvGpuResult};
 
+/// The counters that can be reported with [query_get].
+///
+/// NOTE: ``SamplesPassed`` and the ``ZcullStats`` counters require a ZCULL context to be bound to the channel (see ``Channel::bind_zcull_context``)
+/// and ZCULL statistics to be enabled with [set_zcull_mode], they read garbage otherwise.
+///
+/// [query_get]: fn.query_get.html
+/// [set_zcull_mode]: fn.set_zcull_mode.html
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum ReportCounterType {
     Zero,
@@ -110,6 +118,15 @@ impl From<u32> for ReportControlOperation {
     }
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum MembarScope {
+    /// Make memory operations visible to the other engines of the GPU.
+    Gpu,
+
+    /// Make memory operations visible to the system (CPU included).
+    System,
+}
+
 bitfield! {
     pub struct ReportControl(u32);
     impl Debug;
@@ -155,6 +172,12 @@ impl ReportControl {
     }
 }
 
+impl Default for ReportControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn query_get(
     command_stream: &mut CommandStream,
     gpu_va: GpuVirtualAddress,
@@ -174,3 +197,244 @@ pub fn query_get(
     // Push the command
     command_stream.push(query_get)
 }
+
+/// Enable or disable the collection of ZCULL statistics.
+pub fn set_zcull_mode(command_stream: &mut CommandStream, enable: bool) -> NvGpuResult<()> {
+    // SetZcullStats
+    command_stream.push(Command::new_inline(
+        0x5CC,
+        SubChannelId::ThreeD,
+        enable as u32,
+    ))
+}
+
+pub fn wait_for_idle(command_stream: &mut CommandStream) -> NvGpuResult<()> {
+    command_stream.push(Command::new_inline(0x44, SubChannelId::ThreeD, 0))
+}
+
+pub fn membar(command_stream: &mut CommandStream, scope: MembarScope) -> NvGpuResult<()> {
+    // Wait for all previous 3D work to be done, as the L2 is shared between engines this is enough for GPU scope.
+    wait_for_idle(command_stream)?;
+
+    if scope == MembarScope::System {
+        // Flush pending writes to memory (FB_FLUSH)
+        command_stream.push(Command::new_inline(0x9, SubChannelId::ThreeD, 0))?;
+    }
+
+    Ok(())
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Topology {
+    Points,
+    Lines,
+    LineLoop,
+    LineStrip,
+    Triangles,
+    TriangleStrip,
+    TriangleFan,
+    Quads,
+    QuadStrip,
+    Polygon,
+    Unknown(u32),
+}
+
+impl From<Topology> for u32 {
+    fn from(topology: Topology) -> u32 {
+        match topology {
+            Topology::Points => 0,
+            Topology::Lines => 1,
+            Topology::LineLoop => 2,
+            Topology::LineStrip => 3,
+            Topology::Triangles => 4,
+            Topology::TriangleStrip => 5,
+            Topology::TriangleFan => 6,
+            Topology::Quads => 7,
+            Topology::QuadStrip => 8,
+            Topology::Polygon => 9,
+            Topology::Unknown(val) => val,
+        }
+    }
+}
+
+impl From<u32> for Topology {
+    fn from(topology: u32) -> Topology {
+        match topology {
+            0 => Topology::Points,
+            1 => Topology::Lines,
+            2 => Topology::LineLoop,
+            3 => Topology::LineStrip,
+            4 => Topology::Triangles,
+            5 => Topology::TriangleStrip,
+            6 => Topology::TriangleFan,
+            7 => Topology::Quads,
+            8 => Topology::QuadStrip,
+            9 => Topology::Polygon,
+            val => Topology::Unknown(val),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RenderTargetFormat {
+    /// A8B8G8R8 UNORM.
+    Rgba8Unorm,
+
+    /// A8B8G8R8 SRGB.
+    Rgba8Srgb,
+
+    /// R16G16B16A16 FLOAT.
+    Rgba16Float,
+}
+
+impl RenderTargetFormat {
+    /// Get the size of one pixel in bytes.
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            RenderTargetFormat::Rgba8Unorm | RenderTargetFormat::Rgba8Srgb => 4,
+            RenderTargetFormat::Rgba16Float => 8,
+        }
+    }
+}
+
+impl From<RenderTargetFormat> for u32 {
+    fn from(format: RenderTargetFormat) -> u32 {
+        match format {
+            RenderTargetFormat::Rgba8Unorm => 0xD5,
+            RenderTargetFormat::Rgba8Srgb => 0xD6,
+            RenderTargetFormat::Rgba16Float => 0xCA,
+        }
+    }
+}
+
+/// Bind a pitch linear render target as color target 0 and clear it with the given color.
+///
+/// ``width`` and ``height`` are in pixels, the pitch is assumed to be ``width`` times the size of a pixel.
+pub fn clear_color(
+    command_stream: &mut CommandStream,
+    rt_addr: GpuVirtualAddress,
+    rt_format: RenderTargetFormat,
+    width: u32,
+    height: u32,
+    color: [f32; 4],
+) -> NvGpuResult<()> {
+    let pitch = width
+        .checked_mul(rt_format.bytes_per_pixel())
+        .ok_or(Errno::EINVAL)?;
+
+    if width == 0
+        || width > 0xFFFF
+        || height == 0
+        || height > 0xFFFF
+        || !command_stream.context().address_space().is_mapped(rt_addr)
+    {
+        return Err(Errno::EINVAL);
+    }
+
+    // Color target 0 (SetColorTargetA to SetColorTargetMemory)
+    let mut set_color_target = Command::new(
+        0x200,
+        SubChannelId::ThreeD,
+        CommandSubmissionMode::Increasing,
+    );
+
+    set_color_target.push_address(rt_addr);
+    // For pitch linear targets, the width is the pitch in bytes.
+    set_color_target.push_argument(pitch);
+    set_color_target.push_argument(height);
+    set_color_target.push_argument(u32::from(rt_format));
+    // Pitch layout
+    set_color_target.push_argument(1 << 12);
+
+    command_stream.push(set_color_target)?;
+
+    // Only use color target 0
+    command_stream.push(Command::new_inline(0x487, SubChannelId::ThreeD, 1))?;
+
+    // Surface clip
+    let mut set_surface_clip = Command::new(
+        0x3FD,
+        SubChannelId::ThreeD,
+        CommandSubmissionMode::Increasing,
+    );
+
+    set_surface_clip.push_argument(width << 16);
+    set_surface_clip.push_argument(height << 16);
+
+    command_stream.push(set_surface_clip)?;
+
+    // Clear color
+    let mut set_clear_color = Command::with_capacity(
+        0x360,
+        SubChannelId::ThreeD,
+        CommandSubmissionMode::Increasing,
+        color.len(),
+    );
+
+    for component in color.iter() {
+        set_clear_color.push_f32(*component);
+    }
+
+    command_stream.push(set_clear_color)?;
+
+    // Clear R, G, B and A of target 0
+    let mut clear_surface = Command::new(
+        0x674,
+        SubChannelId::ThreeD,
+        CommandSubmissionMode::Increasing,
+    );
+
+    clear_surface.push_argument(0x3C);
+
+    command_stream.push(clear_surface)
+}
+
+/// Draw ``count`` vertices starting at ``first`` from the currently bound vertex arrays.
+///
+/// NOTE: The shaders and vertex attributes need to be setup beforehand.
+pub fn draw_arrays(
+    command_stream: &mut CommandStream,
+    topology: Topology,
+    first: u32,
+    count: u32,
+) -> NvGpuResult<()> {
+    // Begin
+    let mut begin = Command::new(
+        0x586,
+        SubChannelId::ThreeD,
+        CommandSubmissionMode::Increasing,
+    );
+
+    begin.push_argument(u32::from(topology));
+
+    command_stream.push(begin)?;
+
+    // Vertex buffer first and count
+    let mut vertex_buffer = Command::new(
+        0x50D,
+        SubChannelId::ThreeD,
+        CommandSubmissionMode::Increasing,
+    );
+
+    vertex_buffer.push_argument(first);
+    vertex_buffer.push_argument(count);
+
+    command_stream.push(vertex_buffer)?;
+
+    // End
+    command_stream.push(Command::new_inline(0x585, SubChannelId::ThreeD, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_control_default_sets_reserved_bits() {
+        let report_control = ReportControl::default();
+
+        assert_eq!(report_control.0, ReportControl::new().0);
+        assert_eq!(report_control.reserved(), 0xF);
+        assert_eq!(report_control.0, 0xF000);
+    }
+}
@@ -1 +1,92 @@
+use crate::utils::{Command, CommandStream, CommandSubmissionMode, GpuAllocated, SubChannelId};
+use nvgpu::{NvGpuResult, SurfaceKind};
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SurfaceFormat {
+    B8G8R8A8Unorm,
+    R8G8B8A8Unorm,
+    R32Float,
+    R16Unorm,
+    R8Unorm,
+    Unknown(u32),
+}
+
+impl From<SurfaceFormat> for u32 {
+    fn from(mode: SurfaceFormat) -> u32 {
+        match mode {
+            SurfaceFormat::B8G8R8A8Unorm => 0xCF,
+            SurfaceFormat::R8G8B8A8Unorm => 0xD5,
+            SurfaceFormat::R32Float => 0xE5,
+            SurfaceFormat::R16Unorm => 0xEE,
+            SurfaceFormat::R8Unorm => 0xF3,
+            SurfaceFormat::Unknown(val) => val,
+        }
+    }
+}
+
+impl From<u32> for SurfaceFormat {
+    fn from(mode: u32) -> SurfaceFormat {
+        match mode {
+            0xCF => SurfaceFormat::B8G8R8A8Unorm,
+            0xD5 => SurfaceFormat::R8G8B8A8Unorm,
+            0xE5 => SurfaceFormat::R32Float,
+            0xEE => SurfaceFormat::R16Unorm,
+            0xF3 => SurfaceFormat::R8Unorm,
+            val => SurfaceFormat::Unknown(val),
+        }
+    }
+}
+
+fn set_surface(
+    command_stream: &mut CommandStream,
+    method: u32,
+    surface: &GpuAllocated,
+    format: SurfaceFormat,
+    width: u32,
+    height: u32,
+    pitch: u32,
+) -> NvGpuResult<()> {
+    let mut setup_surface = Command::new(
+        method,
+        SubChannelId::TwoD,
+        CommandSubmissionMode::Increasing,
+    );
+
+    setup_surface.push_argument(u32::from(format));
+    // Memory layout is deduced from the kind of the allocation (1 = Pitch, 0 = BlockLinear)
+    setup_surface.push_argument(!SurfaceKind::from(surface.kind() as u8).is_block_linear() as u32);
+    // Block size = 1 GOB
+    setup_surface.push_argument(0);
+    // Depth = 1
+    setup_surface.push_argument(1);
+    // Layer = 0
+    setup_surface.push_argument(0);
+    setup_surface.push_argument(pitch);
+    setup_surface.push_argument(width);
+    setup_surface.push_argument(height);
+    setup_surface.push_address(surface.gpu_address());
+
+    command_stream.push(setup_surface)
+}
+
+pub fn set_source_surface(
+    command_stream: &mut CommandStream,
+    surface: &GpuAllocated,
+    format: SurfaceFormat,
+    width: u32,
+    height: u32,
+    pitch: u32,
+) -> NvGpuResult<()> {
+    set_surface(command_stream, 0x8C, surface, format, width, height, pitch)
+}
+
+pub fn set_destination_surface(
+    command_stream: &mut CommandStream,
+    surface: &GpuAllocated,
+    format: SurfaceFormat,
+    width: u32,
+    height: u32,
+    pitch: u32,
+) -> NvGpuResult<()> {
+    set_surface(command_stream, 0x80, surface, format, width, height, pitch)
+}
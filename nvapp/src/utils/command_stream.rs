@@ -1,21 +1,44 @@
-use super::GpuAllocated;
+use super::{round_to_page, GpuAllocated};
+use nix::errno::Errno;
 use nvgpu::*;
+use nvhost::RawFence;
+use nvmap::{AllocationFlags, HeapMask};
+
+#[cfg(feature = "command_history")]
+use nvhost::{ChannelError, ErrorNotification};
+#[cfg(feature = "command_history")]
+use nvmap::{Handle, PAGE_SIZE};
 
-use core::convert::TryInto;
 use core::fmt::Debug;
 
 use core::mem::ManuallyDrop;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+#[cfg(feature = "command_history")]
+use std::collections::VecDeque;
 
+/// The way PFIFO reads the arguments of a command.
+///
+/// Increasing and NonIncreasing are the common cases, Inline and IncreasingOnce are useful to save words:
+/// - Inline avoids argument words entirely for a single small value.
+/// - IncreasingOnce allows to write a method and then stream data to the next one (a launch method followed by its data for example) with only one command word.
 #[derive(Debug, PartialEq)]
 pub enum CommandSubmissionMode {
-    /// ?
+    /// Legacy variant of Increasing.
+    ///
+    /// NOTE: Only kept for decoding purposes, use Increasing instead.
     IncreasingOld,
 
     /// Tells PFIFO to read as much arguments as specified by argument count, while automatically incrementing the method value.
     /// This means that each argument will be written to a different method location.
     Increasing,
 
-    /// ?
+    /// Legacy variant of NonIncreasing.
+    ///
+    /// NOTE: Only kept for decoding purposes, use NonIncreasing instead.
     NonIncreasingOld,
 
     /// Tells PFIFO to read as much arguments as specified by argument count.
@@ -23,9 +46,12 @@ pub enum CommandSubmissionMode {
     NonIncreasing,
 
     /// Tells PFIFO to read inline data from bits 28-16 of the command word, thus eliminating the need to pass additional words for the arguments.
+    ///
+    /// NOTE: the inline data shares its bits with the argument count, there is no argument count for this mode.
     Inline,
 
     /// Tells PFIFO to read as much arguments as specified by argument count and automatically increments the method value once only.
+    /// This means that the first argument will be written to the method location and all the others to the next method location.
     IncreasingOnce,
 }
 
@@ -50,6 +76,9 @@ impl From<SubChannelId> for u32 {
     }
 }
 
+/// The maximum count of arguments of a single command word, bigger commands are split when encoded.
+pub const MAX_ARGUMENT_COUNT: usize = 0x7FF;
+
 pub struct Command {
     entry: GpFifoEntry,
     submission_mode: CommandSubmissionMode,
@@ -65,6 +94,20 @@ impl Command {
         Self::new_raw(method, u32::from(sub_channel), submission_mode)
     }
 
+    /// Create a new command with room for ``capacity`` arguments.
+    pub fn with_capacity(
+        method: u32,
+        sub_channel: SubChannelId,
+        submission_mode: CommandSubmissionMode,
+        capacity: usize,
+    ) -> Self {
+        let mut res = Self::new(method, sub_channel, submission_mode);
+
+        res.arguments.reserve_exact(capacity);
+
+        res
+    }
+
     pub fn new_raw(method: u32, sub_channel: u32, submission_mode: CommandSubmissionMode) -> Self {
         let mut res = Command {
             entry: GpFifoEntry(0),
@@ -105,47 +148,117 @@ impl Command {
         self.arguments.push(argument);
     }
 
+    pub fn push_arguments(&mut self, arguments: &[u32]) {
+        assert!(self.submission_mode != CommandSubmissionMode::Inline);
+        self.arguments.extend_from_slice(arguments);
+    }
+
+    /// Push a float argument as its raw bits.
+    pub fn push_f32(&mut self, argument: f32) {
+        self.push_argument(argument.to_bits());
+    }
+
     pub fn push_address(&mut self, address: GpuVirtualAddress) {
         self.push_argument((address >> 32) as u32);
         self.push_argument(address as u32);
     }
 
+    /// Push the given data as little endian words, the last word is padded with zeros if needed.
+    ///
+    /// Exactly ``ceil(data.len() / 4)`` words are pushed, nothing is pushed for empty data.
     pub fn push_inlined_buffer(&mut self, data: &[u8]) {
         let data_len = (data.len() + 3) / 4;
 
-        let rest_len = data.len() % 4;
+        self.arguments.reserve(data_len);
 
-        for i in 0..data_len {
-            // In case the end isn't aligned we need to pad it with one byte.
-            if i == data_len - 1 && rest_len != 0 {
-                let mut temp = [0x0; 4];
+        let previous_len = self.arguments.len();
 
-                temp[..rest_len].copy_from_slice(&data[i * 4..]);
+        for chunk in data.chunks(4) {
+            // In case the end isn't aligned we need to pad it with zeros.
+            let mut temp = [0x0; 4];
 
-                self.push_argument(u32::from_le_bytes(temp));
-            } else {
-                self.push_argument(u32::from_le_bytes(
-                    data[i * 4..(i + 1) * 4].try_into().unwrap(),
-                ))
-            }
+            temp[..chunk.len()].copy_from_slice(chunk);
+
+            self.push_argument(u32::from_le_bytes(temp));
         }
+
+        debug_assert_eq!(self.arguments.len() - previous_len, data_len);
     }
 
-    pub fn into_vec(mut self) -> Vec<u32> {
-        let mut res = Vec::new();
+    /// Get the count of words of the encoded command, see [Command::to_vec].
+    ///
+    /// [Command::to_vec]: struct.Command.html#method.to_vec
+    pub fn encoded_len(&self) -> usize {
+        if self.submission_mode == CommandSubmissionMode::Inline {
+            return 1;
+        }
+
+        let chunk_count = std::cmp::max(1, self.arguments.len().div_ceil(MAX_ARGUMENT_COUNT));
+
+        self.arguments.len() + chunk_count
+    }
 
-        self.entry.set_argument_count(self.arguments.len() as u32);
+    /// Encode the command to raw words.
+    ///
+    /// Commands with more than [MAX_ARGUMENT_COUNT] arguments are split in multiple commands writing to the same methods.
+    ///
+    /// [MAX_ARGUMENT_COUNT]: constant.MAX_ARGUMENT_COUNT.html
+    pub fn to_vec(&self) -> Vec<u32> {
+        // Inline data shares the bits of the argument count.
+        if self.submission_mode == CommandSubmissionMode::Inline {
+            debug_assert!(self.arguments.is_empty());
 
-        res.push(self.entry.0);
-        res.append(&mut self.arguments);
+            return vec![self.entry.0];
+        }
+
+        let mut res = Vec::with_capacity(self.encoded_len());
+
+        let mut entry = GpFifoEntry(self.entry.0);
+        let mut written = 0;
+
+        loop {
+            let chunk = &self.arguments
+                [written..std::cmp::min(written + MAX_ARGUMENT_COUNT, self.arguments.len())];
+
+            entry.set_argument_count(chunk.len() as u32);
+
+            res.push(entry.0);
+            res.extend_from_slice(chunk);
+
+            written += chunk.len();
+
+            if written == self.arguments.len() {
+                break;
+            }
+
+            // Continue where the previous chunk stopped.
+            match self.submission_mode {
+                CommandSubmissionMode::IncreasingOld | CommandSubmissionMode::Increasing => {
+                    entry.set_method(self.entry.method() + written as u32);
+                }
+                CommandSubmissionMode::IncreasingOnce => {
+                    // Only the first argument goes to the first method.
+                    entry.set_method(self.entry.method() + 1);
+                    entry.set_submission_mode(3);
+                }
+                _ => {}
+            }
+        }
 
         res
     }
 
-    pub fn into_gpu_allocated(self) -> NvGpuResult<GpuAllocated> {
+    /// Encode the command to raw words, see [Command::to_vec].
+    ///
+    /// [Command::to_vec]: struct.Command.html#method.to_vec
+    pub fn into_vec(self) -> Vec<u32> {
+        self.to_vec()
+    }
+
+    pub fn into_gpu_allocated(self, context: &Arc<GpuContext>) -> NvGpuResult<GpuAllocated> {
         let vec = self.into_vec();
 
-        let res = GpuAllocated::new(vec.len() * std::mem::size_of::<u32>(), 0x20000)?;
+        let res = GpuAllocated::new(context, vec.len() * std::mem::size_of::<u32>(), 0x20000)?;
 
         let arguments: &mut [u32] = res.map_array_mut()?;
         arguments.copy_from_slice(&vec[..]);
@@ -157,84 +270,530 @@ impl Command {
     }
 }
 
+/// Configuration of a [CommandStream].
+///
+/// The default places command buffers in the generic carveout with write combine.
+/// Using a faster heap (or a pinned IOVMM allocation) can reduce the latency of frequent small submissions
+/// at the cost of consuming a usually more constrained memory pool.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandStreamConfig {
+    /// The heap used to allocate command buffers.
+    pub cmdbuf_heap: HeapMask,
+
+    /// The allocation flags used for command buffers.
+    pub cmdbuf_flags: AllocationFlags,
+
+    /// The maximum count of completed command buffers kept around for reuse.
+    pub pool_capacity: usize,
+}
+
+/// The default count of completed command buffers kept around for reuse.
+pub const COMMAND_BUFFER_POOL_CAPACITY: usize = 16;
+
+impl Default for CommandStreamConfig {
+    fn default() -> Self {
+        CommandStreamConfig {
+            cmdbuf_heap: HeapMask::CARVEOUT_GENERIC,
+            cmdbuf_flags: AllocationFlags::upload(),
+            pool_capacity: COMMAND_BUFFER_POOL_CAPACITY,
+        }
+    }
+}
+
 pub struct CommandStream<'a> {
+    /// The context of the channel.
+    context: &'a Arc<GpuContext>,
+
     /// the inner implementation.
     fifo: ManuallyDrop<GpFifoQueue<'a>>,
 
+    /// The configuration of this stream.
+    config: CommandStreamConfig,
+
     /// A Vec containing allocation to use in fifo.
     command_list: Vec<Command>,
 
+    /// The count of words of the encoded commands in ``command_list``.
+    pending_words: usize,
+
     /// The previous command buffers kept alive to avoid being unmap by Drop during processing of the GPFIFO.
-    in_process: ManuallyDrop<Vec<GpuAllocated>>,
+    ///
+    /// Each command buffer is stored with the fence of its submission, in submission order.
+    in_process: ManuallyDrop<Vec<(GpuAllocated, Option<OutputFence>)>>,
+
+    /// The completed command buffers available for reuse, keyed by their size.
+    pool: HashMap<usize, Vec<GpuAllocated>>,
+
+    /// The count of command buffers in ``pool``.
+    pool_len: usize,
+
+    /// The last submitted commands, used to diagnose faults.
+    #[cfg(feature = "command_history")]
+    history: VecDeque<SubmittedCommand>,
+
+    /// The memory handle the kernel reports channel errors to, see [CommandStream::enable_error_notifier].
+    ///
+    /// [CommandStream::enable_error_notifier]: struct.CommandStream.html#method.enable_error_notifier
+    #[cfg(feature = "command_history")]
+    error_notifier: Option<Handle>,
+}
+
+/// The count of submitted commands kept for fault diagnosis.
+#[cfg(feature = "command_history")]
+pub const COMMAND_HISTORY_SIZE: usize = 64;
+
+/// Describe a previously submitted command.
+#[cfg(feature = "command_history")]
+#[derive(Debug, Clone)]
+pub struct SubmittedCommand {
+    /// The GPU address of the command in its command buffer.
+    pub gpu_address: GpuVirtualAddress,
+    pub method: u32,
+    pub sub_channel: u32,
+    pub arguments: Vec<u32>,
+}
+
+#[cfg(feature = "command_history")]
+impl SubmittedCommand {
+    /// Get the GPU addresses that may be referenced by the arguments (upper 8 bits followed by lower 32 bits).
+    fn referenced_addresses(&self) -> impl Iterator<Item = GpuVirtualAddress> + '_ {
+        self.arguments
+            .windows(2)
+            .filter(|pair| pair[0] <= 0xFF)
+            .map(|pair| (u64::from(pair[0]) << 32) | u64::from(pair[1]))
+    }
 }
 
 impl<'a> Drop for CommandStream<'a> {
+    /// The command buffers in ``in_process`` may still be read by the GPU, they are only freed
+    /// once the last submission is known to be complete.
+    ///
+    /// Entries of a channel are executed in order, so the fence of the last submission covers every command buffer.
+    /// If it cannot be waited on, the command buffers are leaked rather than unmapped under the GPU.
     fn drop(&mut self) {
+        let is_idle = self.fifo.wait_idle().is_ok();
+
         unsafe {
             ManuallyDrop::drop(&mut self.fifo);
-            ManuallyDrop::drop(&mut self.in_process);
+        }
+
+        if is_idle {
+            unsafe {
+                ManuallyDrop::drop(&mut self.in_process);
+            }
         }
     }
 }
 
 impl<'a> CommandStream<'a> {
-    pub fn new(channel: &'a Channel) -> Self {
+    pub fn new(context: &'a Arc<GpuContext>) -> Self {
+        Self::with_config(context, CommandStreamConfig::default())
+    }
+
+    pub fn with_config(context: &'a Arc<GpuContext>, config: CommandStreamConfig) -> Self {
         CommandStream {
-            fifo: ManuallyDrop::new(GpFifoQueue::new(channel)),
+            context,
+            fifo: ManuallyDrop::new(GpFifoQueue::new(context.channel(), context.host_ctrl())),
+            config,
             command_list: Vec::new(),
+            pending_words: 0,
             in_process: ManuallyDrop::new(Vec::new()),
+            pool: HashMap::new(),
+            pool_len: 0,
+            #[cfg(feature = "command_history")]
+            history: VecDeque::with_capacity(COMMAND_HISTORY_SIZE),
+            #[cfg(feature = "command_history")]
+            error_notifier: None,
         }
     }
 
+    /// Create a new stream keeping at most ``capacity`` completed command buffers around for reuse.
+    pub fn with_pool_capacity(context: &'a Arc<GpuContext>, capacity: usize) -> Self {
+        let config = CommandStreamConfig {
+            pool_capacity: capacity,
+            ..CommandStreamConfig::default()
+        };
+
+        Self::with_config(context, config)
+    }
+
+    /// Create a new stream and setup the channel using the cached setup commands.
+    pub fn with_cached_setup(context: &'a Arc<GpuContext>) -> NvGpuResult<Self> {
+        let mut res = Self::new(context);
+
+        res.submit_raw(cached_setup_channel_commands())?;
+        res.wait_idle();
+
+        Ok(res)
+    }
+
+    /// Get the context this stream submits to.
+    pub fn context(&self) -> &'a Arc<GpuContext> {
+        self.context
+    }
+
     pub fn push(&mut self, command: Command) -> NvGpuResult<()> {
+        self.pending_words += command.encoded_len();
         self.command_list.push(command);
 
         Ok(())
     }
 
+    /// Push multiple commands at once, they are submitted together on the next flush.
+    pub fn push_batch<I: IntoIterator<Item = Command>>(&mut self, commands: I) -> NvGpuResult<()> {
+        for command in commands {
+            self.push(command)?;
+        }
+
+        Ok(())
+    }
+
+    /// Discard the pending commands without submitting them.
+    ///
+    /// NOTE: unlike [CommandStream::wait_idle], this doesn't wait for the work already submitted.
+    pub fn clear(&mut self) {
+        self.command_list.clear();
+        self.pending_words = 0;
+    }
+
+    /// Discard the pending commands and wait for all the work submitted on the channel to be complete.
+    ///
+    /// Every command buffer still in flight is then returned to the pool (or freed).
+    pub fn reset(&mut self) -> NvGpuResult<()> {
+        self.clear();
+
+        self.context
+            .channel()
+            .wait_all_idle(self.context.host_ctrl())?;
+
+        self.reclaim_command_buffers()
+    }
+
+    /// Get the size in bytes of the pending commands once encoded.
+    pub fn pending_size(&self) -> usize {
+        self.pending_words * std::mem::size_of::<u32>()
+    }
+
+    /// Make sure a command buffer of at least ``size`` bytes is available for the next flush.
+    ///
+    /// This allows to build a big batch of commands and submit it without allocating on flush.
+    pub fn reserve(&mut self, size: usize) -> NvGpuResult<()> {
+        let commands_gpu = self.get_command_buffer(size)?;
+
+        self.pool
+            .entry(commands_gpu.user_size())
+            .or_default()
+            .push(commands_gpu);
+        self.pool_len += 1;
+
+        Ok(())
+    }
+
+    /// Flush the pending commands if their encoded size is larger than ``threshold`` bytes.
+    ///
+    /// Returns true if a flush happened.
+    pub fn flush_if_larger_than(&mut self, threshold: usize) -> NvGpuResult<bool> {
+        if self.pending_size() <= threshold {
+            return Ok(false);
+        }
+
+        self.flush()?;
+
+        Ok(true)
+    }
+
+    /// Submit the pending commands.
+    ///
+    /// The commands are split in multiple GPFIFO entries if needed, a command is never split between two entries.
     pub fn flush(&mut self) -> NvGpuResult<()> {
-        let mut commands = Vec::new();
+        let mut commands = Vec::with_capacity(self.pending_words);
+        let mut segments = Vec::new();
+        let mut segment_start = 0;
+
+        self.pending_words = 0;
+
+        #[cfg(feature = "command_history")]
+        let mut submitted = Vec::new();
 
         for command in self.command_list.drain(..) {
-            commands.append(&mut command.into_vec());
+            #[cfg(feature = "command_history")]
+            submitted.push(SubmittedCommand {
+                // Relative to the command buffer for now.
+                gpu_address: (commands.len() * std::mem::size_of::<u32>()) as GpuVirtualAddress,
+                method: command.entry.method(),
+                sub_channel: command.entry.sub_channel(),
+                arguments: command.arguments.clone(),
+            });
+
+            let mut words = command.into_vec();
+
+            if commands.len() + words.len() - segment_start > GPFIFO_ENTRY_MAX_WORDS
+                && commands.len() != segment_start
+            {
+                segments.push(commands.len() - segment_start);
+                segment_start = commands.len();
+            }
+
+            commands.append(&mut words);
+        }
+
+        // Nothing to send.
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        segments.push(commands.len() - segment_start);
+
+        self.submit_segments(&commands[..], &segments)?;
+
+        #[cfg(feature = "command_history")]
+        {
+            let base_address = self.in_process.last().unwrap().0.gpu_address();
+
+            for mut command in submitted {
+                command.gpu_address += base_address;
+
+                if self.history.len() == COMMAND_HISTORY_SIZE {
+                    self.history.pop_front();
+                }
+
+                self.history.push_back(command);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the most recent submitted command that likely caused a fault at the given address.
+    ///
+    /// The guess is the command referencing the closest address below the faulting one, it is returned with that address.
+    #[cfg(feature = "command_history")]
+    pub fn check_fault(
+        &self,
+        fault_address: GpuVirtualAddress,
+    ) -> Option<(&SubmittedCommand, GpuVirtualAddress)> {
+        let mut result: Option<(&SubmittedCommand, GpuVirtualAddress)> = None;
+
+        for command in self.history.iter().rev() {
+            for address in command.referenced_addresses() {
+                if address > fault_address {
+                    continue;
+                }
+
+                let is_closer = match result {
+                    Some((_, best_address)) => address > best_address,
+                    None => true,
+                };
+
+                if is_closer {
+                    result = Some((command, address));
+                }
+            }
         }
 
-        let commands_gpu = GpuAllocated::new(commands.len() * std::mem::size_of::<u32>(), 0x20000)?;
+        result
+    }
+
+    /// Make the kernel report the errors of the channel to a memory handle owned by this stream.
+    ///
+    /// NOTE: The channel has only one error notifier, this replaces the one of any other stream of the context.
+    #[cfg(feature = "command_history")]
+    pub fn enable_error_notifier(&mut self) -> NvGpuResult<()> {
+        let size = PAGE_SIZE as u64;
+        let handle = self.context.nvmap().alloc_mapped(
+            size,
+            HeapMask::CARVEOUT_GENERIC,
+            AllocationFlags::readback(),
+            PAGE_SIZE as u32,
+        )?;
+
+        self.context
+            .channel()
+            .set_error_notifier(&handle, 0, size)?;
+        self.error_notifier = Some(handle);
+
+        Ok(())
+    }
+
+    /// Get the error reported by the kernel on the error notifier, if any.
+    ///
+    /// Returns ``ENODEV`` if [CommandStream::enable_error_notifier] wasn't called.
+    ///
+    /// [CommandStream::enable_error_notifier]: struct.CommandStream.html#method.enable_error_notifier
+    #[cfg(feature = "command_history")]
+    pub fn channel_error(&self) -> NvGpuResult<Option<ChannelError>> {
+        let handle = self.error_notifier.as_ref().ok_or(Errno::ENODEV)?;
+
+        Ok(ErrorNotification::read(self.context.nvmap(), handle, 0)?.error())
+    }
+
+    /// Find the command that likely caused the MMU fault reported on the error notifier, see [CommandStream::check_fault].
+    ///
+    /// Returns ``None`` if no MMU fault was reported. The fault address isn't part of the notification,
+    /// it has to be taken from the kernel log.
+    ///
+    /// [CommandStream::check_fault]: struct.CommandStream.html#method.check_fault
+    #[cfg(feature = "command_history")]
+    pub fn check_reported_fault(
+        &self,
+        fault_address: GpuVirtualAddress,
+    ) -> NvGpuResult<Option<(&SubmittedCommand, GpuVirtualAddress)>> {
+        match self.channel_error()? {
+            Some(ChannelError::FifoMmuFault) => Ok(self.check_fault(fault_address)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Submit an already built command buffer, bypassing the pending commands.
+    ///
+    /// NOTE: Buffers bigger than [GPFIFO_ENTRY_MAX_WORDS] are split in multiple GPFIFO entries without
+    /// taking care of command boundaries.
+    pub fn submit_raw(&mut self, words: &[u32]) -> NvGpuResult<()> {
+        let segments: Vec<usize> = words
+            .chunks(GPFIFO_ENTRY_MAX_WORDS)
+            .map(|segment| segment.len())
+            .collect();
+
+        self.submit_segments(words, &segments)
+    }
+
+    /// Submit the given words in one command buffer, with one GPFIFO entry per segment.
+    ///
+    /// The GPFIFO queue is submitted as many times as needed if it becomes full.
+    fn submit_segments(&mut self, words: &[u32], segments: &[usize]) -> NvGpuResult<()> {
+        if words.is_empty() {
+            return Err(Errno::EINVAL);
+        }
+
+        let commands_gpu = self.get_command_buffer(std::mem::size_of_val(words))?;
 
         let fifo_array: &mut [u32] = commands_gpu.map_array_mut()?;
-        fifo_array.copy_from_slice(&commands[..]);
+        fifo_array[..words.len()].copy_from_slice(words);
 
         commands_gpu.flush()?;
         commands_gpu.unmap()?;
-        self.fifo.append(
-            commands_gpu.gpu_address(),
-            (commands_gpu.user_size() as u64) / 4,
-            0,
-        );
 
-        self.in_process.push(commands_gpu);
+        let mut gpu_address = commands_gpu.gpu_address();
+
+        for segment_len in segments {
+            let flags = GpFifoAppendFlags::empty();
+
+            match self.fifo.append(gpu_address, *segment_len as u64, flags) {
+                Err(Errno::ENOSPC) => {
+                    self.fifo.submit()?;
+                    self.fifo.append(gpu_address, *segment_len as u64, flags)?;
+                }
+                res => res?,
+            }
+
+            gpu_address += (segment_len * std::mem::size_of::<u32>()) as GpuVirtualAddress;
+        }
+
         self.fifo.submit()?;
 
+        let fence = match self.fifo.last_fence() {
+            Some(fence) => Some(fence.try_clone()?),
+            None => None,
+        };
+
+        self.in_process.push((commands_gpu, fence));
+
         Ok(())
     }
 
+    /// Get a command buffer of at least ``size`` bytes, reusing a completed one if possible.
+    ///
+    /// Command buffers are allocated with a power of two size (at least a page) to make reuse likely.
+    fn get_command_buffer(&mut self, size: usize) -> NvGpuResult<GpuAllocated> {
+        self.reclaim_command_buffers()?;
+
+        let size = round_to_page(size.next_power_of_two());
+
+        if let Some(commands_gpu) = self.pool.get_mut(&size).and_then(Vec::pop) {
+            self.pool_len -= 1;
+
+            return Ok(commands_gpu);
+        }
+
+        GpuAllocated::new_in_heap(
+            self.context,
+            size,
+            0x20000,
+            self.config.cmdbuf_heap,
+            self.config.cmdbuf_flags,
+        )
+    }
+
+    /// Move the command buffers in ``in_process`` whose fence has signaled to the pool.
+    ///
+    /// Extra command buffers over the pool capacity are freed.
+    ///
+    /// NOTE: Submissions complete in order, the first pending one stops the reclaim.
+    /// A command buffer without a fence is complete once the last submission of the queue is.
+    fn reclaim_command_buffers(&mut self) -> NvGpuResult<()> {
+        let mut completed = 0;
+        let mut is_idle = None;
+
+        for (_, fence) in self.in_process.iter() {
+            let is_signaled = match fence {
+                Some(fence) => fence
+                    .fence()
+                    .wait(self.context.host_ctrl(), Some(Duration::from_secs(0)))?,
+                None => match is_idle {
+                    Some(is_idle) => is_idle,
+                    None => {
+                        *is_idle.insert(self.fifo.wait_idle_timeout(Some(Duration::from_secs(0)))?)
+                    }
+                },
+            };
+
+            if !is_signaled {
+                break;
+            }
+
+            completed += 1;
+        }
+
+        for (commands_gpu, _) in self.in_process.drain(..completed) {
+            if self.pool_len < self.config.pool_capacity {
+                self.pool
+                    .entry(commands_gpu.user_size())
+                    .or_default()
+                    .push(commands_gpu);
+                self.pool_len += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make the next flush wait on the given syncpoint fence (produced by another channel for example).
+    ///
+    /// The wait happens on the GPU side, see [GpFifoQueue::wait_on].
+    pub fn wait_on(&mut self, fence: RawFence) -> NvGpuResult<()> {
+        self.fifo.wait_on(fence)
+    }
+
     pub fn wait_idle(&mut self) {
         self.fifo.wait_idle().unwrap();
     }
 }
 
-pub fn setup_channel(stream: &mut CommandStream) -> NvGpuResult<()> {
+/// Build the commands binding every subchannel to its class.
+fn setup_channel_commands() -> Vec<Command> {
+    let mut res = Vec::new();
+
     // Bind subchannel 0, 3D
     let mut bind_channel_command =
         Command::new(0, SubChannelId::ThreeD, CommandSubmissionMode::Increasing);
     bind_channel_command.push_argument(u32::from(ClassId::MAXWELL_B_3D));
-    stream.push(bind_channel_command)?;
+    res.push(bind_channel_command);
 
     // Bind subchannel 1, Compute
     let mut bind_channel_command =
         Command::new(0, SubChannelId::Compute, CommandSubmissionMode::Increasing);
     bind_channel_command.push_argument(u32::from(ClassId::MAXWELL_B_COMPUTE));
-    stream.push(bind_channel_command)?;
+    res.push(bind_channel_command);
 
     // Bind subchannel 2, Inline To Memory
     let mut bind_channel_command = Command::new(
@@ -243,13 +802,13 @@ pub fn setup_channel(stream: &mut CommandStream) -> NvGpuResult<()> {
         CommandSubmissionMode::Increasing,
     );
     bind_channel_command.push_argument(u32::from(ClassId::INLINE_TO_MEMORY));
-    stream.push(bind_channel_command)?;
+    res.push(bind_channel_command);
 
     // Bind subchannel 3, 2D
     let mut bind_channel_command =
         Command::new(0, SubChannelId::TwoD, CommandSubmissionMode::Increasing);
     bind_channel_command.push_argument(u32::from(ClassId::MAXWELL_A_2D));
-    stream.push(bind_channel_command)?;
+    res.push(bind_channel_command);
 
     // Bind subchannel 4, DMA
     let mut bind_channel_command = Command::new(
@@ -258,9 +817,302 @@ pub fn setup_channel(stream: &mut CommandStream) -> NvGpuResult<()> {
         CommandSubmissionMode::Increasing,
     );
     bind_channel_command.push_argument(u32::from(ClassId::MAXWELL_B_DMA));
-    stream.push(bind_channel_command)?;
+    res.push(bind_channel_command);
+
+    res
+}
+
+/// Get the raw commands of [setup_channel], built only once.
+pub fn cached_setup_channel_commands() -> &'static [u32] {
+    static SETUP_CHANNEL_COMMANDS: OnceLock<Vec<u32>> = OnceLock::new();
+
+    SETUP_CHANNEL_COMMANDS.get_or_init(|| {
+        setup_channel_commands()
+            .into_iter()
+            .flat_map(Command::into_vec)
+            .collect()
+    })
+}
+
+pub fn setup_channel(stream: &mut CommandStream) -> NvGpuResult<()> {
+    for command in setup_channel_commands() {
+        stream.push(command)?;
+    }
 
     stream.wait_idle();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maxwell::compute::memcpy_inline_host_to_device;
+    use crate::maxwell::dma::memcpy_1d;
+    use crate::utils::{initialize, initialize_command_stream, GpuBox};
+    use nvhost::{NvHostChannel, NvHostCtrl};
+
+    /// Decode raw words to the (method, value) writes they do, the way PFIFO reads them.
+    fn decode_method_writes(words: &[u32]) -> Vec<(u32, u32)> {
+        let mut res = Vec::new();
+        let mut position = 0;
+
+        while position < words.len() {
+            let entry = GpFifoEntry(words[position]);
+            position += 1;
+
+            if entry.is_inline() {
+                res.push((entry.method(), entry.inline_arguments()));
+                continue;
+            }
+
+            let arguments = &words[position..position + entry.effective_argument_count()];
+            position += arguments.len();
+
+            for (index, argument) in arguments.iter().enumerate() {
+                let offset = match entry.submission_mode() {
+                    0 | 1 => index as u32,
+                    5 => std::cmp::min(index, 1) as u32,
+                    _ => 0,
+                };
+
+                res.push((entry.method() + offset, *argument));
+            }
+        }
+
+        res
+    }
+
+    #[test]
+    fn inlined_buffer_tail_padding() {
+        let data = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+
+        for (len, expected) in [
+            (0, &[][..]),
+            (1, &[0x11][..]),
+            (2, &[0x2211][..]),
+            (3, &[0x33_2211][..]),
+            (4, &[0x4433_2211][..]),
+            (5, &[0x4433_2211, 0x55][..]),
+            (8, &[0x4433_2211, 0x8877_6655][..]),
+        ] {
+            let mut command = Command::new(
+                0x6C,
+                SubChannelId::Compute,
+                CommandSubmissionMode::NonIncreasing,
+            );
+            command.push_inlined_buffer(&data[..len]);
+
+            assert_eq!(command.arguments, expected, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn split_commands_round_trip_through_decoder() {
+        let arguments: Vec<u32> = (0..MAX_ARGUMENT_COUNT as u32 + 2).collect();
+
+        for (submission_mode, method_offset) in [
+            (
+                CommandSubmissionMode::Increasing,
+                (|index| index) as fn(u32) -> u32,
+            ),
+            (CommandSubmissionMode::NonIncreasing, |_| 0),
+            (CommandSubmissionMode::IncreasingOnce, |index| {
+                std::cmp::min(index, 1)
+            }),
+        ] {
+            let mut command = Command::new(0x100, SubChannelId::Compute, submission_mode);
+            command.push_arguments(&arguments);
+
+            let encoded_len = command.encoded_len();
+            let words = command.into_vec();
+
+            // Split in a full command and a command with the two remaining arguments.
+            assert_eq!(words.len(), encoded_len);
+            assert_eq!(words.len(), arguments.len() + 2);
+            assert_eq!(
+                GpFifoEntry(words[0]).argument_count() as usize,
+                MAX_ARGUMENT_COUNT
+            );
+            assert_eq!(
+                GpFifoEntry(words[MAX_ARGUMENT_COUNT + 1]).argument_count(),
+                2
+            );
+
+            let expected: Vec<(u32, u32)> = arguments
+                .iter()
+                .map(|argument| (0x100 + method_offset(*argument), *argument))
+                .collect();
+
+            assert_eq!(decode_method_writes(&words), expected);
+        }
+    }
+
+    #[test]
+    fn commands_at_split_limit_are_not_split() {
+        let mut command = Command::new(0, SubChannelId::ThreeD, CommandSubmissionMode::Increasing);
+        command.push_arguments(&[0; MAX_ARGUMENT_COUNT]);
+        assert_eq!(command.into_vec().len(), MAX_ARGUMENT_COUNT + 1);
+
+        let command = Command::new(0, SubChannelId::ThreeD, CommandSubmissionMode::Increasing);
+        assert_eq!(command.into_vec(), [0x2000_0000]);
+
+        let command = Command::new_inline(0x585, SubChannelId::ThreeD, 0x42);
+        assert_eq!(decode_method_writes(&command.into_vec()), [(0x585, 0x42)]);
+    }
+
+    #[test]
+    fn cached_setup_matches_fresh_commands() {
+        let fresh: Vec<u32> = setup_channel_commands()
+            .into_iter()
+            .flat_map(Command::into_vec)
+            .collect();
+
+        assert_eq!(cached_setup_channel_commands(), &fresh[..]);
+
+        // One bind command (method word and class) per subchannel.
+        assert_eq!(fresh.len(), 2 * 5);
+        assert_eq!(fresh[..2], [0x2001_0000, 0xB197]);
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn clear_then_flush_submits_nothing() {
+        let context = initialize().unwrap();
+        let mut stream = CommandStream::new(&context);
+
+        let mut command = Command::new(0, SubChannelId::Compute, CommandSubmissionMode::Increasing);
+        command.push_argument(u32::from(ClassId::MAXWELL_B_COMPUTE));
+        stream.push(command).unwrap();
+        assert_ne!(stream.pending_size(), 0);
+
+        stream.clear();
+        assert_eq!(stream.pending_size(), 0);
+
+        stream.flush().unwrap();
+        assert!(stream.in_process.is_empty());
+        assert!(stream.fifo.last_fence().is_none());
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn many_small_batches_are_reclaimed() {
+        let context = initialize().unwrap();
+        let mut stream = initialize_command_stream(&context).unwrap();
+        let buffer = GpuBox::new(&context, 0u32);
+
+        for value in 0..64u32 {
+            memcpy_inline_host_to_device(&mut stream, buffer.gpu_address(), &value.to_le_bytes())
+                .unwrap();
+            stream.flush().unwrap();
+        }
+
+        // A command buffer without a fence must not block the reclaim of the ones after it.
+        let fenceless = GpuAllocated::new(&context, 0x1000, 0x1000).unwrap();
+        stream.in_process.insert(0, (fenceless, None));
+
+        stream.wait_idle();
+        stream.reclaim_command_buffers().unwrap();
+
+        assert!(stream.in_process.is_empty());
+        assert_eq!(*buffer, 63);
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn dropping_a_stream_with_in_flight_work() {
+        let context = initialize().unwrap();
+        let size = 0x10_0000;
+        let src = GpuBox::new_array(&context, size, 0xA5u8).unwrap();
+
+        for _ in 0..16 {
+            let dst = GpuBox::new_array(&context, size, 0u8).unwrap();
+            let mut stream = initialize_command_stream(&context).unwrap();
+
+            for _ in 0..32 {
+                memcpy_1d(
+                    &mut stream,
+                    dst.gpu_address(),
+                    src.gpu_address(),
+                    size as u32,
+                )
+                .unwrap();
+                stream.flush().unwrap();
+            }
+
+            // Dropping the stream waits for the copies before freeing their command buffers.
+            drop(stream);
+
+            assert!(dst[..].iter().all(|value| *value == 0xA5));
+        }
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn reset_waits_for_every_batch() {
+        let context = initialize().unwrap();
+        let mut stream = initialize_command_stream(&context).unwrap();
+        let buffer = GpuBox::new_array(&context, 8, 0u32).unwrap();
+
+        for (index, value) in (1..=8u32).enumerate() {
+            let gpu_address = buffer.gpu_address() + (index * std::mem::size_of::<u32>()) as u64;
+
+            memcpy_inline_host_to_device(&mut stream, gpu_address, &value.to_le_bytes()).unwrap();
+            stream.flush().unwrap();
+        }
+
+        stream.reset().unwrap();
+
+        assert!(stream.in_process.is_empty());
+        assert_eq!(buffer[..], [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn wait_on_blocks_until_syncpoint_reached() {
+        let context_a = initialize().unwrap();
+        let context_b = initialize().unwrap();
+
+        let mut stream_a = initialize_command_stream(&context_a).unwrap();
+        let mut stream_b = initialize_command_stream(&context_b).unwrap();
+
+        let host_channel = NvHostChannel::new("/dev/nvhost-vic", context_b.nvmap()).unwrap();
+        let syncpoint_id = host_channel
+            .allocate_client_managed_syncpoint("wait_on_test")
+            .unwrap();
+
+        let ctrl = NvHostCtrl::new().unwrap();
+        let threshold = ctrl.read_syncpoint(syncpoint_id).unwrap() + 1;
+
+        let buffer = GpuBox::new(&context_b, 0u32);
+
+        stream_b
+            .wait_on(RawFence {
+                id: syncpoint_id,
+                value: threshold,
+            })
+            .unwrap();
+        memcpy_inline_host_to_device(&mut stream_b, buffer.gpu_address(), &42u32.to_le_bytes())
+            .unwrap();
+        stream_b.flush().unwrap();
+
+        // The other channel isn't blocked by the wait.
+        let other_buffer = GpuBox::new(&context_a, 0u32);
+        memcpy_inline_host_to_device(
+            &mut stream_a,
+            other_buffer.gpu_address(),
+            &7u32.to_le_bytes(),
+        )
+        .unwrap();
+        stream_a.flush().unwrap();
+        stream_a.wait_idle();
+
+        assert_eq!(*other_buffer, 7);
+        assert_eq!(*buffer, 0);
+
+        ctrl.increment_syncpoint(syncpoint_id).unwrap();
+        stream_b.wait_idle();
+
+        assert_eq!(*buffer, 42);
+    }
+}
@@ -4,15 +4,23 @@ use core::fmt::Formatter;
 use core::marker::PhantomData;
 use core::ops::Deref;
 use core::ops::DerefMut;
-use std::sync::Mutex;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex};
 
+use nix::errno::Errno;
 use nvgpu::*;
 use nvmap::*;
 
-use super::{align_up, get_as, get_nvmap};
+use super::align_up;
 
 const PAGE_SIZE: u32 = 0x1000;
 
+/// Round a size up to the page size, the granularity of every GPU allocation.
+pub fn round_to_page(size: usize) -> usize {
+    align_up(size, PAGE_SIZE as usize)
+}
+
 /// A Box but availaible to the GPU
 pub struct GpuBox<T: Sized> {
     inner: GpuAllocated,
@@ -20,9 +28,17 @@ pub struct GpuBox<T: Sized> {
 }
 
 impl<T: Sized> GpuBox<T> {
-    pub fn new_with_alignment(x: T, align: usize) -> GpuBox<T> {
-        let inner =
-            GpuAllocated::new(std::mem::size_of::<T>(), align).expect("Cannot allocate GpuBox!");
+    pub fn new_with_alignment(context: &Arc<GpuContext>, x: T, align: usize) -> GpuBox<T> {
+        Self::try_new_with_alignment(context, x, align).expect("Cannot allocate GpuBox!")
+    }
+
+    /// Allocate a new GpuBox, returning an error instead of panicking on failure.
+    pub fn try_new_with_alignment(
+        context: &Arc<GpuContext>,
+        x: T,
+        align: usize,
+    ) -> NvGpuResult<GpuBox<T>> {
+        let inner = GpuAllocated::new(context, std::mem::size_of::<T>(), align)?;
 
         let mut res = GpuBox {
             inner,
@@ -32,13 +48,23 @@ impl<T: Sized> GpuBox<T> {
         *res = x;
 
         // Flush inital data
-        res.flush().expect("Cannot flush initial GpuBox data");
+        res.flush()?;
+
+        Ok(res)
+    }
 
-        res
+    pub fn new(context: &Arc<GpuContext>, x: T) -> GpuBox<T> {
+        Self::new_with_alignment(context, x, std::mem::align_of::<T>())
     }
 
-    pub fn new(x: T) -> GpuBox<T> {
-        Self::new_with_alignment(x, std::mem::align_of::<T>())
+    /// Allocate an array of ``len`` elements initialized to ``init``, see [GpuVec].
+    ///
+    /// [GpuVec]: struct.GpuVec.html
+    pub fn new_array(context: &Arc<GpuContext>, len: usize, init: T) -> NvGpuResult<GpuVec<T>>
+    where
+        T: Copy,
+    {
+        GpuVec::new(context, len, init)
     }
 
     pub fn unmap(&self) -> NvMapResult<()> {
@@ -53,6 +79,15 @@ impl<T: Sized> GpuBox<T> {
         self.inner.flush()
     }
 
+    /// Invalidate the CPU cache and get a reference to the value, to read back values written by the GPU.
+    pub fn read(&self) -> NvMapResult<&T> {
+        let res = self.inner.map()?;
+
+        self.inner.invalidate()?;
+
+        Ok(res)
+    }
+
     pub fn gpu_address(&self) -> GpuVirtualAddress {
         self.inner.gpu_address()
     }
@@ -62,11 +97,16 @@ impl<T: Sized> GpuBox<T> {
     }
 }
 
+/// Reading through ``Deref`` invalidates the CPU cache first so values written by the GPU are observed.
+///
+/// NOTE: Pending CPU writes must be flushed with [GpuBox::flush] before, they could be discarded otherwise.
+///
+/// [GpuBox::flush]: struct.GpuBox.html#method.flush
 impl<T: Sized> Deref for GpuBox<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        self.inner.map().expect("Cannot map")
+        self.read().expect("Cannot map")
     }
 }
 
@@ -76,10 +116,142 @@ impl<T: Sized> DerefMut for GpuBox<T> {
     }
 }
 
+/// A fixed size array availaible to the GPU.
+pub struct GpuVec<T: Sized> {
+    inner: GpuAllocated,
+    len: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Sized + Copy> GpuVec<T> {
+    /// Allocate an array of ``len`` elements initialized to ``init``.
+    ///
+    /// Returns ``EINVAL`` if the array would be empty.
+    pub fn new_with_alignment(
+        context: &Arc<GpuContext>,
+        len: usize,
+        init: T,
+        align: usize,
+    ) -> NvGpuResult<GpuVec<T>> {
+        let size = len
+            .checked_mul(std::mem::size_of::<T>())
+            .ok_or(Errno::EINVAL)?;
+
+        if size == 0 {
+            return Err(Errno::EINVAL);
+        }
+
+        let inner = GpuAllocated::new(context, size, align)?;
+
+        let mut res = GpuVec {
+            inner,
+            len,
+            phantom: PhantomData,
+        };
+
+        res.fill(init);
+
+        // Flush inital data
+        res.flush()?;
+
+        Ok(res)
+    }
+
+    pub fn new(context: &Arc<GpuContext>, len: usize, init: T) -> NvGpuResult<GpuVec<T>> {
+        Self::new_with_alignment(context, len, init, std::mem::align_of::<T>())
+    }
+}
+
+impl<T: Sized> GpuVec<T> {
+    /// Get the count of elements of the array.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn unmap(&self) -> NvMapResult<()> {
+        self.inner.unmap()
+    }
+
+    pub fn invalidate(&self) -> NvMapResult<()> {
+        self.inner.invalidate()
+    }
+
+    pub fn flush(&self) -> NvMapResult<()> {
+        self.inner.flush()
+    }
+
+    pub fn gpu_address(&self) -> GpuVirtualAddress {
+        self.inner.gpu_address()
+    }
+
+    /// Get the size of the array in bytes.
+    pub fn user_size(&self) -> usize {
+        self.inner.user_size()
+    }
+
+    /// Invalidate the CPU cache and get a reference to the array, to read back values written by the GPU.
+    pub fn read(&self) -> NvMapResult<&[T]> {
+        let res = self.inner.map_array()?;
+
+        self.inner.invalidate()?;
+
+        Ok(res)
+    }
+}
+
+/// Reading through ``Deref`` invalidates the CPU cache first, see [GpuBox].
+///
+/// [GpuBox]: struct.GpuBox.html
+impl<T: Sized> Deref for GpuVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.read().expect("Cannot map")
+    }
+}
+
+impl<T: Sized> DerefMut for GpuVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.inner.map_array_mut().expect("Cannot map_mut")
+    }
+}
+
+// GpuAllocated, GpuBox and GpuVec can be shared with other threads (GpuBox and GpuVec as long as T allows it).
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<GpuAllocated>();
+    assert_send_sync::<GpuBox<u32>>();
+    assert_send_sync::<GpuVec<u32>>();
+};
+
+/// GPU memory mapped in the address space.
+///
+/// The CPU mapping is created on first access and cached until [GpuAllocated::unmap] is called.
+///
+/// GpuAllocated is ``Send`` and ``Sync``, the handle is behind a mutex and the cached mapping is atomic.
+///
+/// NOTE: [GpuAllocated::map] and [GpuAllocated::map_mut] hand out references to the same memory,
+/// the caller must ensure a mutable reference never coexists with any other reference to the allocation.
+/// The references must also not be used after [GpuAllocated::unmap].
+///
+/// [GpuAllocated::map]: struct.GpuAllocated.html#method.map
+/// [GpuAllocated::map_mut]: struct.GpuAllocated.html#method.map_mut
+/// [GpuAllocated::unmap]: struct.GpuAllocated.html#method.unmap
 pub struct GpuAllocated {
+    /// The context the allocation belongs to, kept alive until the allocation is dropped.
+    context: Arc<GpuContext>,
     handle: Mutex<Handle>,
+    /// The cached CPU address of the mapping, null if not mapped.
+    cpu_address: AtomicPtr<u8>,
     gpu_address: GpuVirtualAddress,
     user_size: usize,
+    mapping_size: u64,
+    kind: i16,
 }
 
 impl Debug for GpuAllocated {
@@ -88,113 +260,337 @@ impl Debug for GpuAllocated {
         f.debug_struct("GpuAllocated")
             .field("handle", &self.handle)
             .field("gpu_address", &self.gpu_address)
+            .field("mapping_size", &self.mapping_size)
+            .field("kind", &self.kind)
             .finish()
     }
 }
 
 impl GpuAllocated {
-    // TODO: kind
-    pub fn new(user_size: usize, align: usize) -> NvGpuResult<Self> {
-        let align = if align < PAGE_SIZE as usize {
-            PAGE_SIZE
+    pub fn new(context: &Arc<GpuContext>, user_size: usize, align: usize) -> NvGpuResult<Self> {
+        Self::new_in_heap(
+            context,
+            user_size,
+            align,
+            HeapMask::CARVEOUT_GENERIC,
+            AllocationFlags::upload(),
+        )
+    }
+
+    pub fn new_in_heap(
+        context: &Arc<GpuContext>,
+        user_size: usize,
+        align: usize,
+        heap_mask: HeapMask,
+        flags: AllocationFlags,
+    ) -> NvGpuResult<Self> {
+        Self::new_internal(context, user_size, align, heap_mask, flags, KIND_PITCH)
+    }
+
+    /// Allocate GPU memory mapped with the given memory kind (block linear surfaces for example).
+    ///
+    /// Block linear kinds are mapped with big pages, the size and alignment are raised to ``COMPRESSION_PAGE_SIZE``.
+    /// Returns ``EINVAL`` if the kind is out of range or if ``align`` isn't a power of two.
+    pub fn new_with_kind(
+        context: &Arc<GpuContext>,
+        user_size: usize,
+        align: usize,
+        kind: i16,
+    ) -> NvGpuResult<Self> {
+        Self::new_internal(
+            context,
+            user_size,
+            align,
+            HeapMask::CARVEOUT_GENERIC,
+            AllocationFlags::upload(),
+            kind,
+        )
+    }
+
+    fn new_internal(
+        context: &Arc<GpuContext>,
+        user_size: usize,
+        align: usize,
+        heap_mask: HeapMask,
+        flags: AllocationFlags,
+        kind: i16,
+    ) -> NvGpuResult<Self> {
+        // Kinds are 8 bits wide on the GPU side.
+        if !(0..=0xFF).contains(&kind) || (align != 0 && !align.is_power_of_two()) {
+            return Err(Errno::EINVAL);
+        }
+
+        let surface_kind = SurfaceKind::from(kind as u8);
+
+        // Block linear surfaces must cover whole big pages.
+        let page_size = if surface_kind.is_block_linear() {
+            COMPRESSION_PAGE_SIZE
         } else {
-            align as u32
+            PAGE_SIZE
         };
 
+        let align = std::cmp::max(align, page_size as usize) as u32;
+
         // Ensure allocation are at least page sized all the time.
-        let size = align_up(user_size as u32, PAGE_SIZE);
+        let size = align_up(user_size, page_size as usize) as u64;
 
-        let nvmap = get_nvmap();
-        let nvgpu_as = get_as();
+        let nvmap = context.nvmap();
+        let nvgpu_as = context.address_space();
 
         let nvmap_handle = nvmap.create(size)?;
-        nvmap.allocate(
-            &nvmap_handle,
-            HeapMask::CARVEOUT_GENERIC,
-            AllocationFlags::HANDLE_WRITE_COMBINE,
-            align,
+        // The memory kind of the allocation must match the one of the GPU mapping.
+        if surface_kind.is_block_linear() {
+            nvmap.allocate_with_kind(&nvmap_handle, heap_mask, flags, align, kind as u8)?;
+        } else {
+            nvmap.allocate(&nvmap_handle, heap_mask, flags, align)?;
+        }
+
+        let (compr_kind, incompr_kind) = surface_kind.mapping_kinds();
+        let mapping = nvgpu_as.map_buffer_extended(
+            nvmap_handle.fd,
+            0,
+            compr_kind,
+            incompr_kind,
+            page_size,
+            0,
+            0,
+            0,
         )?;
-        let gpu_address = nvgpu_as.map_buffer(&nvmap_handle, 0, PAGE_SIZE, 0)?;
 
-        Ok(GpuAllocated::from_raw(nvmap_handle, gpu_address, user_size))
+        let mut res = GpuAllocated::from_raw(context, nvmap_handle, mapping.address, user_size);
+        res.kind = kind;
+
+        // The kernel reports 0 when the whole buffer is mapped.
+        if mapping.size != 0 {
+            res.mapping_size = mapping.size;
+        }
+
+        Ok(res)
     }
 
-    pub fn from_raw(handle: Handle, gpu_address: GpuVirtualAddress, user_size: usize) -> Self {
+    pub fn from_raw(
+        context: &Arc<GpuContext>,
+        handle: Handle,
+        gpu_address: GpuVirtualAddress,
+        user_size: usize,
+    ) -> Self {
+        let mapping_size = handle.size();
+
+        let cpu_address = AtomicPtr::new(handle.addr().unwrap_or(std::ptr::null_mut()));
+
         GpuAllocated {
+            context: context.clone(),
             handle: Mutex::new(handle),
+            cpu_address,
             gpu_address,
             user_size,
+            mapping_size,
+            kind: KIND_PITCH,
         }
     }
 
-    pub fn map<T: Sized>(&self) -> NvMapResult<&T> {
+    /// Get the CPU address of the allocation, mapping it on first use.
+    fn cpu_address(&self) -> NvMapResult<*mut u8> {
+        let cached = self.cpu_address.load(Ordering::Acquire);
+
+        if !cached.is_null() {
+            return Ok(cached);
+        }
+
         let mut handle = self.handle.lock().unwrap();
-        get_nvmap().map(&mut *handle)?;
+        self.context.nvmap().map(&mut handle)?;
 
         let mapped_address = handle.addr().expect("Handle address is null!");
+        self.cpu_address.store(mapped_address, Ordering::Release);
+
+        Ok(mapped_address)
+    }
 
-        let ptr = mapped_address as *mut T;
+    pub fn map<T: Sized>(&self) -> NvMapResult<&T> {
+        let ptr = self.cpu_address()? as *mut T;
 
         Ok(unsafe { ptr.as_mut().unwrap() })
     }
 
     pub fn map_mut<T: Sized>(&self) -> NvMapResult<&mut T> {
-        let mut handle = self.handle.lock().unwrap();
-        get_nvmap().map(&mut *handle)?;
-
-        let mapped_address = handle.addr().expect("Handle address is null!");
-
-        let ptr = mapped_address as *mut T;
+        let ptr = self.cpu_address()? as *mut T;
 
         Ok(unsafe { ptr.as_mut().unwrap() })
     }
 
     pub fn map_array<T: Sized>(&self) -> NvMapResult<&[T]> {
-        let mut handle = self.handle.lock().unwrap();
-        get_nvmap().map(&mut *handle)?;
-
-        let mapped_address = handle.addr().expect("Handle address is null!");
-
-        let ptr = mapped_address as *mut T;
+        let ptr = self.cpu_address()? as *mut T;
 
         Ok(unsafe { std::slice::from_raw_parts(ptr, self.user_size() / std::mem::size_of::<T>()) })
     }
 
     pub fn map_array_mut<T: Sized>(&self) -> NvMapResult<&mut [T]> {
-        let mut handle = self.handle.lock().unwrap();
-        get_nvmap().map(&mut *handle)?;
-
-        let mapped_address = handle.addr().expect("Handle address is null!");
-
-        let ptr = mapped_address as *mut T;
+        let ptr = self.cpu_address()? as *mut T;
 
         Ok(unsafe {
             std::slice::from_raw_parts_mut(ptr, self.user_size() / std::mem::size_of::<T>())
         })
     }
 
+    /// Remove the CPU mapping, every reference previously returned by the map functions becomes invalid.
     pub fn unmap(&self) -> NvMapResult<()> {
         let mut handle = self.handle.lock().unwrap();
-        get_nvmap().unmap(&mut handle)
+        self.cpu_address
+            .store(std::ptr::null_mut(), Ordering::Release);
+        self.context.nvmap().unmap(&mut handle)
+    }
+
+    /// Write a value at the start of the allocation and flush it.
+    ///
+    /// The CPU mapping is left as found.
+    pub fn store<T: Copy>(&self, value: &T) -> NvGpuResult<()> {
+        let size = std::mem::size_of::<T>();
+
+        if size > self.user_size {
+            return Err(Errno::EINVAL);
+        }
+
+        let nvmap = self.context.nvmap();
+        let mut handle = self.handle.lock().unwrap();
+        let was_mapped = handle.addr().is_some();
+
+        nvmap.map(&mut handle)?;
+
+        let ptr = handle.addr().expect("Handle address is null!") as *mut T;
+        unsafe { ptr.write_unaligned(*value) };
+
+        handle.flush_range(nvmap, 0, size as u32)?;
+
+        if !was_mapped {
+            nvmap.unmap(&mut handle)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a value from the start of the allocation after invalidating it.
+    ///
+    /// The CPU mapping is left as found.
+    pub fn load<T: Copy>(&self) -> NvGpuResult<T> {
+        let size = std::mem::size_of::<T>();
+
+        if size > self.user_size {
+            return Err(Errno::EINVAL);
+        }
+
+        let nvmap = self.context.nvmap();
+        let mut handle = self.handle.lock().unwrap();
+        let was_mapped = handle.addr().is_some();
+
+        nvmap.map(&mut handle)?;
+        handle.invalidate_range(nvmap, 0, size as u32)?;
+
+        let ptr = handle.addr().expect("Handle address is null!") as *const T;
+        let result = unsafe { ptr.read_unaligned() };
+
+        if !was_mapped {
+            nvmap.unmap(&mut handle)?;
+        }
+
+        Ok(result)
     }
 
     pub fn invalidate(&self) -> NvMapResult<()> {
         let handle = self.handle.lock().unwrap();
-        get_nvmap().invalidate(&handle, 0, handle.size())
+
+        // Nothing to maintain without a CPU mapping.
+        if handle.addr().is_none() {
+            return Ok(());
+        }
+
+        self.context
+            .nvmap()
+            .invalidate(&handle, 0, self.cache_size(&handle))
     }
 
     pub fn flush(&self) -> NvMapResult<()> {
         let handle = self.handle.lock().unwrap();
-        get_nvmap().writeback_invalidate(&handle, 0, handle.size())
+
+        // Nothing to maintain without a CPU mapping.
+        if handle.addr().is_none() {
+            return Ok(());
+        }
+
+        self.context
+            .nvmap()
+            .writeback_invalidate(&handle, 0, self.cache_size(&handle))
+    }
+
+    /// Invalidate the given range of the allocation.
+    ///
+    /// Returns ``EINVAL`` if the range is out of the allocation.
+    pub fn invalidate_range(&self, offset: usize, size: usize) -> NvMapResult<()> {
+        let handle = self.handle.lock().unwrap();
+
+        // Nothing to maintain without a CPU mapping.
+        if handle.addr().is_none() {
+            return Ok(());
+        }
+
+        let (offset, size) = cache_range(offset, size)?;
+
+        handle.invalidate_range(self.context.nvmap(), offset, size)
+    }
+
+    /// Flush the given range of the allocation.
+    ///
+    /// Returns ``EINVAL`` if the range is out of the allocation.
+    pub fn flush_range(&self, offset: usize, size: usize) -> NvMapResult<()> {
+        let handle = self.handle.lock().unwrap();
+
+        // Nothing to maintain without a CPU mapping.
+        if handle.addr().is_none() {
+            return Ok(());
+        }
+
+        let (offset, size) = cache_range(offset, size)?;
+
+        handle.flush_range(self.context.nvmap(), offset, size)
+    }
+
+    /// Get the size covered by cache maintenance operations: the mapped span, bounded by the handle size.
+    fn cache_size(&self, handle: &Handle) -> u32 {
+        self.mapping_size.min(handle.size()) as u32
     }
 
     pub fn gpu_address(&self) -> GpuVirtualAddress {
         self.gpu_address
     }
 
+    /// Get the size requested at allocation time.
     pub fn user_size(&self) -> usize {
         self.user_size
     }
+
+    /// Get the size actually allocated, rounded up to the page size.
+    ///
+    /// NOTE: Cache maintenance operations cover this whole size, not only ``user_size``.
+    pub fn allocated_size(&self) -> usize {
+        self.handle.lock().unwrap().size() as usize
+    }
+
+    /// Get the size of the GPU mapping as reported by the kernel.
+    pub fn mapping_size(&self) -> u64 {
+        self.mapping_size
+    }
+
+    /// Get the memory kind used by the GPU mapping.
+    pub fn kind(&self) -> i16 {
+        self.kind
+    }
+}
+
+/// Convert a range to the 32 bits offset and size used by cache maintenance operations.
+fn cache_range(offset: usize, size: usize) -> NvMapResult<(u32, u32)> {
+    match (u32::try_from(offset), u32::try_from(size)) {
+        (Ok(offset), Ok(size)) => Ok((offset, size)),
+        _ => Err(Errno::EINVAL),
+    }
 }
 
 impl Drop for GpuAllocated {
@@ -203,9 +599,51 @@ impl Drop for GpuAllocated {
 
         self.unmap().expect("Cannot unmap from CPU side");
 
-        let nvgpu_as = get_as();
+        let nvgpu_as = self.context.address_space();
         nvgpu_as
             .unmap_buffer(self.gpu_address())
             .expect("Cannot unmap GpuAllocated!");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_to_page_rounds_up_to_page_boundary() {
+        assert_eq!(round_to_page(0), 0);
+        assert_eq!(round_to_page(1), 0x1000);
+        assert_eq!(round_to_page(0xFFF), 0x1000);
+        assert_eq!(round_to_page(0x1000), 0x1000);
+        assert_eq!(round_to_page(0x1001), 0x2000);
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn cache_maintenance_with_partial_page_mapping_size() {
+        let context = crate::utils::initialize().unwrap();
+        let mut allocation = GpuAllocated::new(&context, 0x1234, 0).unwrap();
+
+        assert_eq!(allocation.allocated_size(), 0x2000);
+        allocation.map::<u8>().unwrap();
+
+        // A mapping size that isn't a multiple of the page size is used as is.
+        allocation.mapping_size = 0x1800;
+        {
+            let handle = allocation.handle.lock().unwrap();
+            assert_eq!(allocation.cache_size(&handle), 0x1800);
+        }
+        allocation.flush().unwrap();
+        allocation.invalidate().unwrap();
+
+        // It is bounded by the handle size otherwise.
+        allocation.mapping_size = 0x2800;
+        {
+            let handle = allocation.handle.lock().unwrap();
+            assert_eq!(allocation.cache_size(&handle), 0x2000);
+        }
+        allocation.flush().unwrap();
+        allocation.invalidate().unwrap();
+    }
+}
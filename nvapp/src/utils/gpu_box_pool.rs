@@ -0,0 +1,347 @@
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+
+use nvgpu::*;
+use nvmap::*;
+
+use super::{align_up, round_to_page, GpuAllocated};
+
+/// The size of a CPU cache line, slots never share one so cache maintenance on a slot doesn't affect its neighbours.
+const CACHE_LINE_SIZE: usize = 64;
+
+/// The free ranges of a backing allocation.
+#[derive(Debug, PartialEq, Eq)]
+struct FreeRanges {
+    /// The free ranges as (offset, size) pairs sorted by offset.
+    ranges: Vec<(usize, usize)>,
+}
+
+impl FreeRanges {
+    /// Create a new instance with the whole ``size`` free.
+    fn new(size: usize) -> Self {
+        FreeRanges {
+            ranges: vec![(0, size)],
+        }
+    }
+
+    /// Try to reserve a range of the given size, aligned once added to ``base``, returning its offset.
+    fn reserve(&mut self, base: usize, size: usize, align: usize) -> Option<usize> {
+        for index in 0..self.ranges.len() {
+            let (offset, free_size) = self.ranges[index];
+            let aligned_offset = align_up(base + offset, align) - base;
+            let padding = aligned_offset - offset;
+
+            if padding + size > free_size {
+                continue;
+            }
+
+            let rest = free_size - padding - size;
+
+            self.ranges.remove(index);
+
+            if rest != 0 {
+                self.ranges.insert(index, (aligned_offset + size, rest));
+            }
+
+            if padding != 0 {
+                self.ranges.insert(index, (offset, padding));
+            }
+
+            return Some(aligned_offset);
+        }
+
+        None
+    }
+
+    /// Give back a range, merging it with its neighbours.
+    fn release(&mut self, offset: usize, size: usize) {
+        let index = self
+            .ranges
+            .iter()
+            .position(|(free_offset, _)| *free_offset > offset)
+            .unwrap_or(self.ranges.len());
+
+        self.ranges.insert(index, (offset, size));
+
+        // Merge with the next range.
+        if index + 1 < self.ranges.len() {
+            let (next_offset, next_size) = self.ranges[index + 1];
+
+            if offset + size == next_offset {
+                self.ranges[index].1 += next_size;
+                self.ranges.remove(index + 1);
+            }
+        }
+
+        // Merge with the previous range.
+        if index > 0 {
+            let (previous_offset, previous_size) = self.ranges[index - 1];
+
+            if previous_offset + previous_size == offset {
+                self.ranges[index - 1].1 += self.ranges[index].1;
+                self.ranges.remove(index);
+            }
+        }
+    }
+}
+
+/// A backing allocation of a pool.
+struct PoolChunk {
+    allocation: GpuAllocated,
+
+    /// The CPU address of the allocation, kept mapped for the lifetime of the pool.
+    cpu_address: *mut u8,
+
+    /// The free ranges of the allocation.
+    free_ranges: FreeRanges,
+}
+
+// SAFETY: The CPU address points to a process wide mapping owned by the allocation, chunks are only accessed behind the pool mutex.
+unsafe impl Send for PoolChunk {}
+
+impl PoolChunk {
+    fn new(context: &Arc<GpuContext>, size: usize, align: usize) -> NvGpuResult<Self> {
+        let allocation = GpuAllocated::new(context, size, align)?;
+        let cpu_address = allocation.map_array_mut::<u8>()?.as_mut_ptr();
+        let size = allocation.allocated_size();
+
+        Ok(PoolChunk {
+            allocation,
+            cpu_address,
+            free_ranges: FreeRanges::new(size),
+        })
+    }
+
+    /// Try to reserve a range of the given size and alignment, returning its offset.
+    fn reserve(&mut self, size: usize, align: usize) -> Option<usize> {
+        let base = self.allocation.gpu_address() as usize;
+
+        self.free_ranges.reserve(base, size, align)
+    }
+
+    /// Give back a range.
+    fn release(&mut self, offset: usize, size: usize) {
+        self.free_ranges.release(offset, size)
+    }
+}
+
+// The pool and its boxes can be shared with other threads.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<GpuBoxPool>();
+    assert_send_sync::<PooledGpuBox<'static, u32>>();
+};
+
+/// A pool sub-allocating GPU objects out of a few large allocations.
+///
+/// This avoids creating an nvmap handle (of at least a page) per small object.
+pub struct GpuBoxPool {
+    context: Arc<GpuContext>,
+    chunks: Mutex<Vec<PoolChunk>>,
+    chunk_size: usize,
+}
+
+impl GpuBoxPool {
+    /// Create a new pool, backing allocations are created on demand with the given size.
+    pub fn new(context: &Arc<GpuContext>, chunk_size: usize) -> Self {
+        GpuBoxPool {
+            context: context.clone(),
+            chunks: Mutex::new(Vec::new()),
+            chunk_size: round_to_page(chunk_size),
+        }
+    }
+
+    pub fn alloc<T: Sized>(&self, x: T) -> NvGpuResult<PooledGpuBox<'_, T>> {
+        self.alloc_with_alignment(x, std::mem::align_of::<T>())
+    }
+
+    pub fn alloc_with_alignment<T: Sized>(
+        &self,
+        x: T,
+        align: usize,
+    ) -> NvGpuResult<PooledGpuBox<'_, T>> {
+        // Slots are made of whole cache lines.
+        let size = align_up(std::mem::size_of::<T>().max(1), CACHE_LINE_SIZE);
+        let align = align.max(std::mem::align_of::<T>()).max(CACHE_LINE_SIZE);
+
+        let mut chunks = self.chunks.lock().unwrap();
+
+        let mut reserved = None;
+
+        for (chunk_index, chunk) in chunks.iter_mut().enumerate() {
+            if let Some(offset) = chunk.reserve(size, align) {
+                reserved = Some((chunk_index, offset));
+                break;
+            }
+        }
+
+        let (chunk_index, offset) = match reserved {
+            Some(reserved) => reserved,
+            None => {
+                let mut chunk = PoolChunk::new(
+                    &self.context,
+                    self.chunk_size.max(round_to_page(size + align)),
+                    align,
+                )?;
+                let offset = chunk.reserve(size, align).unwrap();
+
+                chunks.push(chunk);
+
+                (chunks.len() - 1, offset)
+            }
+        };
+
+        let chunk = &chunks[chunk_index];
+        let ptr = unsafe { chunk.cpu_address.add(offset) } as *mut T;
+
+        unsafe { ptr.write(x) };
+
+        let res = PooledGpuBox {
+            pool: self,
+            chunk_index,
+            offset,
+            size,
+            ptr,
+            gpu_address: chunk.allocation.gpu_address() + offset as GpuVirtualAddress,
+            phantom: PhantomData,
+        };
+
+        // Flush inital data
+        chunk.allocation.flush_range(offset, size)?;
+
+        Ok(res)
+    }
+
+    /// Get the count of backing allocations.
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.lock().unwrap().len()
+    }
+}
+
+/// A GpuBox sub-allocated from a [GpuBoxPool], its slot is given back to the pool on drop.
+///
+/// [GpuBoxPool]: struct.GpuBoxPool.html
+pub struct PooledGpuBox<'a, T: Sized> {
+    pool: &'a GpuBoxPool,
+    chunk_index: usize,
+    offset: usize,
+    size: usize,
+    ptr: *mut T,
+    gpu_address: GpuVirtualAddress,
+    phantom: PhantomData<T>,
+}
+
+// SAFETY: The pointer is owned by this box like a Box<T> would, the pool itself is Sync.
+unsafe impl<'a, T: Sized + Send> Send for PooledGpuBox<'a, T> {}
+
+// SAFETY: See above, shared access only hands out &T.
+unsafe impl<'a, T: Sized + Sync> Sync for PooledGpuBox<'a, T> {}
+
+impl<'a, T: Sized> PooledGpuBox<'a, T> {
+    /// Invalidate the slot of this box in the backing allocation.
+    pub fn invalidate(&self) -> NvMapResult<()> {
+        self.pool.chunks.lock().unwrap()[self.chunk_index]
+            .allocation
+            .invalidate_range(self.offset, self.size)
+    }
+
+    /// Flush the slot of this box in the backing allocation.
+    pub fn flush(&self) -> NvMapResult<()> {
+        self.pool.chunks.lock().unwrap()[self.chunk_index]
+            .allocation
+            .flush_range(self.offset, self.size)
+    }
+
+    pub fn gpu_address(&self) -> GpuVirtualAddress {
+        self.gpu_address
+    }
+}
+
+impl<'a, T: Sized> Deref for PooledGpuBox<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<'a, T: Sized> DerefMut for PooledGpuBox<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr }
+    }
+}
+
+impl<'a, T: Sized> Drop for PooledGpuBox<'a, T> {
+    fn drop(&mut self) {
+        unsafe { std::ptr::drop_in_place(self.ptr) };
+
+        self.pool.chunks.lock().unwrap()[self.chunk_index].release(self.offset, self.size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_splits_free_ranges() {
+        let mut free_ranges = FreeRanges::new(0x1000);
+
+        assert_eq!(free_ranges.reserve(0x10000, 0x40, 0x40), Some(0));
+        assert_eq!(free_ranges.reserve(0x10000, 0x40, 0x40), Some(0x40));
+        assert_eq!(free_ranges.ranges, [(0x80, 0xF80)]);
+
+        // The alignment padding stays free.
+        assert_eq!(free_ranges.reserve(0x10000, 0x40, 0x100), Some(0x100));
+        assert_eq!(free_ranges.ranges, [(0x80, 0x80), (0x140, 0xEC0)]);
+
+        // The padding is reused by a smaller allocation.
+        assert_eq!(free_ranges.reserve(0x10000, 0x80, 0x40), Some(0x80));
+        assert_eq!(free_ranges.ranges, [(0x140, 0xEC0)]);
+
+        // The alignment is relative to the base address.
+        assert_eq!(free_ranges.reserve(0x10040, 0x40, 0x100), Some(0x1C0));
+    }
+
+    #[test]
+    fn reserve_fails_when_no_range_fits() {
+        let mut free_ranges = FreeRanges::new(0x100);
+
+        assert_eq!(free_ranges.reserve(0, 0x80, 0x40), Some(0));
+        assert_eq!(free_ranges.reserve(0, 0xC0, 0x40), None);
+        assert_eq!(free_ranges.reserve(0, 0x80, 0x40), Some(0x80));
+        assert!(free_ranges.ranges.is_empty());
+        assert_eq!(free_ranges.reserve(0, 0x40, 0x40), None);
+    }
+
+    #[test]
+    fn release_merges_with_neighbours() {
+        let mut free_ranges = FreeRanges::new(0x200);
+
+        for expected in [0, 0x80, 0x100, 0x180] {
+            assert_eq!(free_ranges.reserve(0, 0x80, 0x80), Some(expected));
+        }
+
+        // No neighbour is free.
+        free_ranges.release(0x80, 0x80);
+        assert_eq!(free_ranges.ranges, [(0x80, 0x80)]);
+
+        // Merge with the previous range.
+        free_ranges.release(0x100, 0x80);
+        assert_eq!(free_ranges.ranges, [(0x80, 0x100)]);
+
+        // Merge with the next range.
+        free_ranges.release(0, 0x80);
+        assert_eq!(free_ranges.ranges, [(0, 0x180)]);
+
+        // Merge with both.
+        assert_eq!(free_ranges.reserve(0, 0x80, 0x80), Some(0));
+        free_ranges.release(0x180, 0x80);
+        assert_eq!(free_ranges.ranges, [(0x80, 0x180)]);
+        free_ranges.release(0, 0x80);
+        assert_eq!(free_ranges, FreeRanges::new(0x200));
+    }
+}
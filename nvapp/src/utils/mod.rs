@@ -1,18 +1,15 @@
 use core::ops::{BitAnd, Not};
-use num_traits::Num;
+use num_traits::{CheckedAdd, Num};
 use nvgpu::*;
-use nvmap::*;
+use std::sync::Arc;
 
 pub mod command_stream;
 pub mod gpu_box;
+pub mod gpu_box_pool;
 
 pub use command_stream::*;
 pub use gpu_box::*;
 
-static mut NVMAP_INSTANCE: *mut NvMap = std::ptr::null_mut();
-static mut NVAS_INSTANCE: *mut AddressSpace = std::ptr::null_mut();
-static mut NVHOST_CTRL_INSTANCE: *mut NvHostGpuCtrl = std::ptr::null_mut();
-
 /// Align the address to the next alignment.
 ///
 /// The given number should be a power of two to get coherent results!
@@ -21,7 +18,12 @@ static mut NVHOST_CTRL_INSTANCE: *mut NvHostGpuCtrl = std::ptr::null_mut();
 ///
 /// Panics on underflow if align is 0.
 /// Panics on overflow if the expression `addr + (align - 1)` overflows.
+/// Panics in debug builds if align isn't a power of two, see [align_up_checked] for a non panicking version.
+///
+/// [align_up_checked]: fn.align_up_checked.html
 pub fn align_up<T: Num + Not<Output = T> + BitAnd<Output = T> + Copy>(addr: T, align: T) -> T {
+    debug_assert!(is_power_of_two(align));
+
     align_down(addr + (align - T::one()), align)
 }
 
@@ -36,96 +38,50 @@ pub fn align_down<T: Num + Not<Output = T> + BitAnd<Output = T> + Copy>(addr: T,
     addr & !(align - T::one())
 }
 
-/// align_up, but checks if addr overflows
-pub fn align_up_checked(addr: usize, align: usize) -> Option<usize> {
-    match addr & (align - 1) {
-        0 => Some(addr),
-        _ => addr.checked_add(align - (addr % align)),
-    }
-}
-
-fn init_nvmap() -> std::io::Result<()> {
-    let nvmap = NvMap::new()?;
-    let nvmap_box = Box::new(nvmap);
-    let nvmap_ref = Box::leak(nvmap_box);
-
-    unsafe {
-        NVMAP_INSTANCE = nvmap_ref as *mut NvMap;
+/// align_up, but returns None if addr overflows or if the alignment isn't a power of two.
+pub fn align_up_checked<T: Num + Not<Output = T> + BitAnd<Output = T> + CheckedAdd + Copy>(
+    addr: T,
+    align: T,
+) -> Option<T> {
+    if !is_power_of_two(align) {
+        return None;
     }
 
-    Ok(())
+    addr.checked_add(&(align - T::one()))
+        .map(|addr| align_down(addr, align))
 }
 
-fn init_address_space() -> std::io::Result<()> {
-    let nvhost_gpu_ctrl = get_nvhost_gpu_ctrl();
-    let address_space = nvhost_gpu_ctrl.allocate_address_space(0x10000, 0)?;
-    let address_space_box = Box::new(address_space);
-    let address_space_ref = Box::leak(address_space_box);
-
-    unsafe {
-        NVAS_INSTANCE = address_space_ref as *mut AddressSpace;
-    }
-
-    Ok(())
+/// Check if the given number is a power of two (zero isn't).
+fn is_power_of_two<T: Num + BitAnd<Output = T> + Copy>(value: T) -> bool {
+    value != T::zero() && (value & (value - T::one())) == T::zero()
 }
 
-fn init_nvhost_gpu_control() -> std::io::Result<()> {
-    let nvhost_ctrl = NvHostGpuCtrl::new()?;
-    let nvhost_ctrl_box = Box::new(nvhost_ctrl);
-    let nvhost_ctrl_ref = Box::leak(nvhost_ctrl_box);
-
-    unsafe {
-        NVHOST_CTRL_INSTANCE = nvhost_ctrl_ref as *mut NvHostGpuCtrl;
-    }
-
-    Ok(())
-}
-
-pub fn initialize() -> std::io::Result<(nvgpu::Channel, nvgpu::GpuCharacteristics)> {
-    init_nvhost_gpu_control()?;
-    init_nvmap()?;
-    init_address_space()?;
-
-    let nvhost_gpu_ctrl = get_nvhost_gpu_ctrl();
-    let nvmap = get_nvmap();
-    let nvgpu_as = get_as();
-    let nvtsg_channel = nvhost_gpu_ctrl.open_tsg()?;
-
-    let nvgpu_channel = nvhost_gpu_ctrl.open_channel(-1, nvmap, nvgpu_as, Some(&nvtsg_channel))?;
-
-    Ok((nvgpu_channel, nvhost_gpu_ctrl.get_characteristics()?))
+/// Open the GPU context, with the object contexts of every class bound by [setup_channel] allocated on its channel.
+///
+/// [setup_channel]: command_stream/fn.setup_channel.html
+pub fn initialize() -> NvGpuResult<Arc<GpuContext>> {
+    let mut context = GpuContext::open()?;
+
+    // Every subchannel bound by setup_channel needs its object context.
+    context.channel_mut().allocate_contexts(&[
+        ClassId::MAXWELL_B_3D,
+        ClassId::MAXWELL_B_COMPUTE,
+        ClassId::INLINE_TO_MEMORY,
+        ClassId::MAXWELL_A_2D,
+        ClassId::MAXWELL_B_DMA,
+    ])?;
+
+    Ok(Arc::new(context))
 }
 
-pub fn initialize_command_stream<'a>(
-    channel: &'a nvgpu::Channel,
-) -> NvGpuResult<CommandStream<'a>> {
-    let mut command_stream = CommandStream::new(&channel);
+pub fn initialize_command_stream(context: &Arc<GpuContext>) -> NvGpuResult<CommandStream<'_>> {
+    let mut command_stream = CommandStream::new(context);
 
     setup_channel(&mut command_stream)?;
 
     Ok(command_stream)
 }
 
-pub fn get_nvmap() -> &'static mut NvMap {
-    unsafe { NVMAP_INSTANCE.as_mut().expect("NvMap not initialized") }
-}
-
-pub fn get_as() -> &'static mut AddressSpace {
-    unsafe {
-        NVAS_INSTANCE
-            .as_mut()
-            .expect("AddressSpace not initialized")
-    }
-}
-
-pub fn get_nvhost_gpu_ctrl() -> &'static mut NvHostGpuCtrl {
-    unsafe {
-        NVHOST_CTRL_INSTANCE
-            .as_mut()
-            .expect("NvHostGpuCtrl not initialized")
-    }
-}
-
 /// Creates a fake C-like enum, where all bit values are accepted.
 ///
 /// This is mainly useful for FFI constructs. In C, an enum is allowed to take
@@ -21,13 +21,13 @@ impl GpFifoDecoder {
             next_index: 0,
         };
 
-        let args_range = 0..Self::arguments_count(&res.raw_entry);
+        let args_range = 0..res.raw_entry.effective_argument_count();
 
         for i in args_range {
             res.arguments.insert(i as u32, None);
         }
 
-        if res.raw_entry.submission_mode() == 4 {
+        if res.raw_entry.is_inline() {
             res.arguments
                 .insert(0, Some(res.raw_entry.inline_arguments()));
             res.next_index += 1;
@@ -45,21 +45,13 @@ impl GpFifoDecoder {
     }
 
     pub fn is_complete(&self) -> bool {
-        self.raw_entry.submission_mode() == 4 || self.next_index == self.raw_entry.argument_count()
-    }
-
-    pub fn arguments_count(entry: &GpFifoEntry) -> usize {
-        if entry.submission_mode() == 4 {
-            1
-        } else {
-            entry.argument_count() as usize
-        }
+        self.raw_entry.is_inline() || self.next_index == self.raw_entry.argument_count()
     }
 
     pub fn to_method(raw_value: u32) -> String {
         let mut res = Vec::new();
         let entry = GpFifoEntry(raw_value);
-        let args_range = 0..Self::arguments_count(&entry);
+        let args_range = 0..entry.effective_argument_count();
 
         let arguments_list: Vec<String> = args_range
             .map(|value| format!("uint32_t arg{}", value))
@@ -91,7 +83,7 @@ impl GpFifoDecoder {
 
         let mut argument_offset = entry.method();
 
-        for i in 0..Self::arguments_count(&entry) {
+        for i in 0..entry.effective_argument_count() {
             res.push(format!(
                 "    REGISTERS[0x{:x}] = arg{};\n",
                 argument_offset, i
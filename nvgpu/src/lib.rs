@@ -5,16 +5,21 @@ extern crate nix;
 #[macro_use]
 extern crate bitfield;
 
+use bitflags::bitflags;
 use nix::errno::Errno;
 use nix::poll::{PollFd, PollFlags};
 use nvhost::*;
 use nvmap::*;
 
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::FromRawFd;
+use std::os::unix::io::IntoRawFd;
 use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+use std::time::Duration;
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 #[allow(non_camel_case_types)]
@@ -38,21 +43,268 @@ impl From<ClassId> for u32 {
     }
 }
 
+/// Represent a GPU architecture family.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Arch {
+    Kepler,
+    Maxwell,
+    Pascal,
+    Volta,
+    Unknown(u32),
+}
+
+impl From<u32> for Arch {
+    fn from(arch: u32) -> Arch {
+        match arch {
+            0xE0 | 0xF0 | 0x100 => Arch::Kepler,
+            0x110 | 0x120 => Arch::Maxwell,
+            0x130 => Arch::Pascal,
+            0x140 | 0x150 => Arch::Volta,
+            val => Arch::Unknown(val),
+        }
+    }
+}
+
 /// The result of NvGpu operations.
 pub type NvGpuResult<T> = std::result::Result<T, Errno>;
 
+/// Error returned by [GpuCharacteristics::require_arch] when the GPU isn't supported.
+///
+/// [GpuCharacteristics::require_arch]: struct.GpuCharacteristics.html#method.require_arch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedArchError {
+    /// The name of the detected chip.
+    pub chip_name: String,
+
+    /// The architecture family of the detected chip.
+    pub arch: Arch,
+
+    /// The raw architecture value of the detected chip.
+    pub raw_arch: u32,
+}
+
+impl std::fmt::Display for UnsupportedArchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unsupported chip {:?} ({:?}, arch 0x{:x})",
+            self.chip_name, self.arch, self.raw_arch
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedArchError {}
+
+impl From<UnsupportedArchError> for Errno {
+    fn from(_: UnsupportedArchError) -> Errno {
+        Errno::ENODEV
+    }
+}
+
 /// Represent a virtual address in the GPU address space.
 pub type GpuVirtualAddress = u64;
 
+/// Represent a buffer mapped in a GPU address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// The virtual address of the mapping.
+    pub address: GpuVirtualAddress,
+
+    /// The size of the mapping as returned by the kernel.
+    pub size: u64,
+}
+
 /// Represent an nvgpu address space instance.
 pub struct AddressSpace {
     /// The inner file descriptor of this instance.
     file: File,
+
+    /// The buffers currently mapped in this address space (address to mapping size).
+    mappings: Mutex<BTreeMap<GpuVirtualAddress, u64>>,
+}
+
+bitflags! {
+    /// Flags of the compression bits of a buffer.
+    pub struct CompbitsFlags: u32 {
+        /// The buffer uses compression bits.
+        const HAS_COMPBITS = 1 << 0;
+        /// The compression bits can be mapped.
+        const MAPPABLE = 1 << 1;
+        /// The IOVA of the buffer is discontiguous.
+        const DISCONTIG_IOVA = 1 << 2;
+    }
+}
+
+/// Represent the compression bits information of a mapped buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompbitsInfo {
+    /// The size of the compression bits mapping window.
+    pub window_size: u64,
+
+    /// The comptag line index of the start of the window.
+    pub window_ctagline: u32,
+
+    /// The comptag line index of the buffer mapping.
+    pub mapping_ctagline: u32,
+
+    /// The flags of the compression bits.
+    pub flags: CompbitsFlags,
 }
 
 pub type GpFifoRawOffset = u64;
 
+bitflags! {
+    /// Flags of a GPFIFO submission.
+    pub struct SubmitFlags: u32 {
+        /// Wait on the input fence before executing the entries.
+        const FENCE_WAIT = 1 << 0;
+        /// Return a fence signaled when the entries are executed.
+        const FENCE_GET = 1 << 1;
+        /// The entries are in hardware format.
+        const HW_FORMAT = 1 << 2;
+        /// Fences are sync_file file descriptors instead of syncpoint thresholds.
+        const SYNC_FENCE = 1 << 3;
+        /// Do not emit a wait for idle before the entries.
+        const SUPPRESS_WFI = 1 << 4;
+    }
+}
+
+/// Fence of a GPFIFO submission.
+///
+/// The representation depends on [SubmitFlags::SYNC_FENCE].
+///
+/// [SubmitFlags::SYNC_FENCE]: struct.SubmitFlags.html#associatedconstant.SYNC_FENCE
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Fence {
+    /// A syncpoint threshold.
+    Syncpoint { id: SyncPointId, value: u32 },
+
+    /// A sync_file file descriptor.
+    Fd(RawFd),
+}
+
+impl Fence {
+    /// Create a fence from the raw fence returned by the kernel for the given submission flags.
+    pub fn from_raw(raw: RawFence, flags: SubmitFlags) -> Self {
+        if flags.contains(SubmitFlags::SYNC_FENCE) {
+            Fence::Fd(raw.id as RawFd)
+        } else {
+            Fence::Syncpoint {
+                id: raw.id,
+                value: raw.value,
+            }
+        }
+    }
+
+    /// Get the raw representation of this fence.
+    pub fn to_raw(self) -> RawFence {
+        match self {
+            Fence::Syncpoint { id, value } => RawFence { id, value },
+            Fence::Fd(fd) => RawFence {
+                id: fd as SyncPointId,
+                value: 0,
+            },
+        }
+    }
+
+    /// Check if this fence is a sync_file file descriptor.
+    pub fn is_fd(&self) -> bool {
+        matches!(self, Fence::Fd(_))
+    }
+
+    /// Wait for this fence to be signaled or for the timeout to expire.
+    ///
+    /// Returns false if the timeout expired.
+    ///
+    /// Syncpoint fences are waited on with the given nvhost control instance.
+    ///
+    /// NOTE: ``None`` means an infinite timeout, a zero timeout only polls the fence.
+    pub fn wait(self, ctrl: &NvHostCtrl, timeout: Option<Duration>) -> NvGpuResult<bool> {
+        let timeout_ms = match timeout {
+            Some(timeout) => timeout.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+
+        match self {
+            Fence::Fd(fd) => wait_sync_fd(fd, timeout_ms),
+            Fence::Syncpoint { id, value } => match ctrl.wait_syncpoint(id, value, timeout_ms) {
+                Err(Errno::ETIMEDOUT) => Ok(false),
+                res => res.map(|_| true),
+            },
+        }
+    }
+}
+
+/// Wait for a sync_file file descriptor to be signaled, returns false if the timeout expired.
+fn wait_sync_fd(fd: RawFd, timeout_ms: i32) -> NvGpuResult<bool> {
+    let mut poll_fds = [PollFd::new(fd, PollFlags::POLLOUT | PollFlags::POLLIN)];
+
+    Ok(poll_retry(&mut poll_fds, timeout_ms)? != 0)
+}
+
+/// An owned fence returned by a submission, the sync_file file descriptor (if any) is closed on drop.
+#[derive(Debug)]
+pub struct OutputFence {
+    fence: Fence,
+}
+
+impl OutputFence {
+    /// Create a new OutputFence taking the ownership of the given fence.
+    pub fn new(fence: Fence) -> Self {
+        OutputFence { fence }
+    }
+
+    /// Get the fence.
+    pub fn fence(&self) -> Fence {
+        self.fence
+    }
+
+    /// Duplicate this fence, the sync_file file descriptor (if any) is duplicated.
+    pub fn try_clone(&self) -> NvGpuResult<Self> {
+        let fence = match self.fence {
+            Fence::Fd(fd) => Fence::Fd(nix::unistd::dup(fd)?),
+            fence => fence,
+        };
+
+        Ok(OutputFence { fence })
+    }
+
+    /// Give up the ownership of the fence, the caller is now responsible for closing the file descriptor.
+    pub fn into_fence(self) -> Fence {
+        let fence = self.fence;
+
+        std::mem::forget(self);
+
+        fence
+    }
+}
+
+impl Drop for OutputFence {
+    fn drop(&mut self) {
+        if let Fence::Fd(fd) = self.fence {
+            let _ = nix::unistd::close(fd);
+        }
+    }
+}
+
+bitflags! {
+    /// Flags of a GPFIFO entry appended with [GpFifoQueue::append], at their position in the 64 bits entry.
+    ///
+    /// [GpFifoQueue::append]: struct.GpFifoQueue.html#method.append
+    pub struct GpFifoAppendFlags: u64 {
+        /// Only fetch the entry if it is not already cached.
+        const FETCH_CONDITIONAL = 1 << 0;
+        /// The entry is privileged.
+        const PRIVILEGED = 1 << 40;
+        /// The entry is a subroutine.
+        const SUBROUTINE = 1 << 41;
+        /// Do not prefetch the entry, the previous entries are completely fetched first (also known as "sync wait").
+        const NO_PREFETCH = 1 << 63;
+    }
+}
+
 bitfield! {
+  #[derive(Clone, Copy, PartialEq, Eq)]
   pub struct GpFifoEntry(u32);
   impl Debug;
   #[inline]
@@ -74,14 +326,44 @@ bitfield! {
   pub submission_mode, set_submission_mode: 31, 29;
 }
 
+impl GpFifoEntry {
+    /// Check if the argument of this entry is inlined in the entry itself.
+    pub fn is_inline(&self) -> bool {
+        self.submission_mode() == 4
+    }
+
+    /// Get the count of arguments of this entry, including the inlined one.
+    pub fn effective_argument_count(&self) -> usize {
+        if self.is_inline() {
+            1
+        } else {
+            self.argument_count() as usize
+        }
+    }
+}
+
 pub const GPFIFO_QUEUE_SIZE: usize = 0x800;
 
+/// The maximum count of words of a single GPFIFO entry.
+pub const GPFIFO_ENTRY_MAX_WORDS: usize = 0x1F_FFFF;
+
 pub type GpFifoRawQueue = [GpFifoRawOffset; GPFIFO_QUEUE_SIZE];
 
+/// Pack a GPFIFO entry pointing to ``command_count`` words at the given address.
+fn pack_gpfifo_entry(
+    gpu_address: GpuVirtualAddress,
+    command_count: u64,
+    flags: GpFifoAppendFlags,
+) -> GpFifoRawOffset {
+    gpu_address | (command_count << 42) | flags.bits()
+}
+
 pub struct GpFifoQueue<'a> {
     channel: &'a Channel,
+    ctrl: &'a NvHostCtrl,
     queue: GpFifoRawQueue,
-    waiting_fence: Option<RawFence>,
+    waiting_fence: Option<OutputFence>,
+    pending_wait: Option<Fence>,
     position: usize,
 }
 
@@ -92,62 +374,211 @@ impl<'a> Drop for GpFifoQueue<'a> {
 }
 
 impl<'a> GpFifoQueue<'a> {
-    pub fn new(channel: &'a Channel) -> Self {
+    /// Create a new queue submitting to the given channel, syncpoint fences are waited on with ``ctrl``.
+    pub fn new(channel: &'a Channel, ctrl: &'a NvHostCtrl) -> Self {
         GpFifoQueue {
             channel,
+            ctrl,
             queue: [0; GPFIFO_QUEUE_SIZE],
             waiting_fence: None,
+            pending_wait: None,
             position: 0,
         }
     }
 
-    pub fn append(&mut self, gpu_address: GpuVirtualAddress, command_count: u64, _flags: u32) {
+    /// Get the channel this queue submits to.
+    pub fn channel(&self) -> &'a Channel {
+        self.channel
+    }
+
+    /// Get the fence of the last submission if it may still be pending.
+    pub fn last_fence(&self) -> Option<&OutputFence> {
+        self.waiting_fence.as_ref()
+    }
+
+    /// Get the count of entries that can still be appended before a submit is needed.
+    ///
+    /// NOTE: This is the free space of the host side staging queue, not the occupancy of the
+    /// hardware GPFIFO ring which may still be consuming previously submitted entries.
+    /// The hardware GET/PUT pointers live in the USERD of the channel, nvgpu only hands it to
+    /// userland for usermode submission (Volta and later) so they cannot be read here.
+    pub fn free_entries(&self) -> usize {
+        GPFIFO_QUEUE_SIZE - self.position
+    }
+
+    /// Append an entry to the queue.
+    ///
+    /// Returns ``ENOSPC`` if the queue is full, it needs to be submitted first.
+    pub fn append(
+        &mut self,
+        gpu_address: GpuVirtualAddress,
+        command_count: u64,
+        flags: GpFifoAppendFlags,
+    ) -> NvGpuResult<()> {
         if self.position >= GPFIFO_QUEUE_SIZE {
-            panic!("No more space availaible in GpFifoCommandBuilder");
+            return Err(Errno::ENOSPC);
         }
 
-        // TODO: use flags
-        self.queue[self.position] = gpu_address | (command_count << 42);
+        self.queue[self.position] = pack_gpfifo_entry(gpu_address, command_count, flags);
         self.position += 1;
+
+        Ok(())
     }
 
+    /// Submit the appended entries.
+    ///
+    /// NOTE: Entries of a channel are executed in order, the previous submission doesn't need to be waited on.
     pub fn submit(&mut self) -> NvGpuResult<()> {
-        let waiting_fence = self.waiting_fence.take();
+        // A syncpoint wait requires a syncpoint based submission, the output fence is a syncpoint too in this case.
+        let (input_fence, flags) = match self.pending_wait.take() {
+            Some(fence) => (
+                Some(fence),
+                SubmitFlags::FENCE_GET | SubmitFlags::FENCE_WAIT,
+            ),
+            None => (None, SubmitFlags::FENCE_GET | SubmitFlags::SYNC_FENCE),
+        };
 
-        // 1 << 3 => fds
-        let mut flags = 1 << 1 | 1 << 3;
+        let res =
+            self.channel
+                .submit_gpfifo(&self.queue[..self.position], input_fence, flags.bits());
 
-        // We have something to wait on from past request.
-        if waiting_fence.is_some() {
-            flags |= 1;
+        if res.is_err() {
+            self.pending_wait = input_fence;
         }
 
-        self.waiting_fence =
-            self.channel
-                .submit_gpfifo(&self.queue[..self.position], waiting_fence, flags)?;
-
+        self.waiting_fence = res?;
         self.position = 0;
 
         Ok(())
     }
 
-    pub fn wait_idle(&mut self) -> nix::Result<()> {
-        if let Some(fence) = self.waiting_fence.take() {
-            let fd = fence.id as RawFd;
+    /// Submit the pending entries and wait for them to complete within the given timeout.
+    ///
+    /// Returns ``ETIMEDOUT`` if the deadline expired, the work is still pending on the GPU in this case.
+    ///
+    /// NOTE: The nvgpu submit ioctl has no per submission timeout, this deadline is only enforced on the CPU side.
+    /// Killing a stuck submission is still the job of the channel watchdog (``NVGPU_IOCTL_CHANNEL_SET_TIMEOUT_EX``).
+    pub fn submit_with_timeout(&mut self, timeout: Duration) -> NvGpuResult<()> {
+        self.submit()?;
+
+        if self.wait_idle_timeout(Some(timeout))? {
+            Ok(())
+        } else {
+            Err(Errno::ETIMEDOUT)
+        }
+    }
+
+    /// Make the next submission wait on the given syncpoint fence, the wait happens on the GPU side.
+    ///
+    /// Returns ``EINVAL`` if the syncpoint id is invalid and ``EBUSY`` if a wait is already pending,
+    /// only one input fence can be given to a submission.
+    pub fn wait_on(&mut self, fence: RawFence) -> NvGpuResult<()> {
+        if fence.id < 0 {
+            return Err(Errno::EINVAL);
+        }
 
-            let mut poll_fds = [PollFd::new(fd, PollFlags::POLLOUT | PollFlags::POLLIN)];
+        let syncpoint_count = self.ctrl.get_characteristics()?.num_syncpts();
 
-            nix::poll::poll(&mut poll_fds, -1)?;
+        if fence.id as u32 >= syncpoint_count {
+            return Err(Errno::EINVAL);
         }
 
+        if self.pending_wait.is_some() {
+            return Err(Errno::EBUSY);
+        }
+
+        self.pending_wait = Some(Fence::Syncpoint {
+            id: fence.id,
+            value: fence.value,
+        });
+
         Ok(())
     }
+
+    /// Wait for the last submission to be complete, without any timeout.
+    ///
+    /// See [GpFifoQueue::wait_idle_timeout] to detect a stuck channel.
+    ///
+    /// [GpFifoQueue::wait_idle_timeout]: struct.GpFifoQueue.html#method.wait_idle_timeout
+    pub fn wait_idle(&mut self) -> nix::Result<()> {
+        self.wait_idle_timeout(None).map(|_| ())
+    }
+
+    /// Wait for the last submission to be complete or for the timeout to expire.
+    ///
+    /// Returns false if the timeout expired, in this case the fence is kept to allow a retry.
+    ///
+    /// NOTE: ``None`` means an infinite timeout.
+    pub fn wait_idle_timeout(&mut self, timeout: Option<Duration>) -> NvGpuResult<bool> {
+        if let Some(fence) = self.waiting_fence.as_ref().map(OutputFence::fence) {
+            if !fence.wait(self.ctrl, timeout)? {
+                return Ok(false);
+            }
+
+            self.waiting_fence = None;
+        }
+
+        Ok(true)
+    }
+}
+
+/// Configuration of a [Channel], see [Channel::with_config] and [NvHostGpuCtrl::open_channel].
+///
+/// [Channel]: struct.Channel.html
+/// [Channel::with_config]: struct.Channel.html#method.with_config
+/// [NvHostGpuCtrl::open_channel]: struct.NvHostGpuCtrl.html#method.open_channel
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelConfig {
+    /// The count of entries of the GPFIFO ring.
+    pub gpfifo_entries: usize,
+
+    /// The class of the object context to allocate.
+    pub object_class: ClassId,
+
+    /// The flags of the object context allocation.
+    pub flags: u32,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            gpfifo_entries: GPFIFO_QUEUE_SIZE,
+            object_class: ClassId::MAXWELL_B_3D,
+            flags: 0,
+        }
+    }
+}
+
+impl ChannelConfig {
+    /// The configuration of a compute only channel, only the ``MAXWELL_B_COMPUTE`` object context is allocated.
+    ///
+    /// The compute class is expected to be bound on subchannel 1 (like a regular channel), other subchannels must not be used.
+    pub fn compute() -> Self {
+        ChannelConfig {
+            object_class: ClassId::MAXWELL_B_COMPUTE,
+            ..ChannelConfig::default()
+        }
+    }
 }
 
 /// Represent an nvgpu channel.
+///
+/// NOTE: the channel is disabled and unbound from its [TSGChannel] on drop, it must be dropped before the [AddressSpace] it is bound to.
+///
+/// [TSGChannel]: struct.TSGChannel.html
+/// [AddressSpace]: struct.AddressSpace.html
 pub struct Channel {
     /// The actual nvhost channel.
     inner: NvHostChannel,
+
+    /// The object contexts allocated on this channel as (class, object id) pairs.
+    object_contexts: Vec<(ClassId, u64)>,
+
+    /// A duplicate of the file descriptor of the TSG this channel is bound to, used to unbind it on drop.
+    bound_tsg: Mutex<Option<File>>,
+
+    /// Whether this instance is a clone of another one, clones don't disable the channel on drop.
+    is_clone: bool,
 }
 
 #[repr(C, align(8))]
@@ -210,21 +641,318 @@ pub struct GpuCharacteristics {
 }
 
 impl GpuCharacteristics {
+    /// Get the name of the chip (``gm20b`` for example).
+    ///
+    /// NOTE: if the kernel didn't report a name, the name is derived from the architecture and implementation.
     pub fn chip_name(&self) -> &str {
-        core::str::from_utf8(&self.chip_name[..])
-            .unwrap()
-            .trim_end_matches('\0')
+        let name = core::str::from_utf8(&self.chip_name[..])
+            .unwrap_or("")
+            .trim_end_matches('\0');
+
+        if name.is_empty() {
+            self.known_chip_name().unwrap_or("unknown")
+        } else {
+            name
+        }
+    }
+
+    /// Get the name of the chip from its architecture and implementation if known.
+    pub fn known_chip_name(&self) -> Option<&'static str> {
+        match (self.arch, self.gpu_impl) {
+            (0xE0, 0xA) => Some("gk20a"),
+            (0x120, 0xB) => Some("gm20b"),
+            (0x130, 0xB) => Some("gp10b"),
+            (0x150, 0xB) => Some("gv11b"),
+            _ => None,
+        }
+    }
+
+    /// Get the architecture family of the GPU.
+    pub fn arch(&self) -> Arch {
+        Arch::from(self.arch)
+    }
+
+    /// Ensure the GPU belongs to one of the given architecture families.
+    ///
+    /// Returns an [UnsupportedArchError] describing the detected chip otherwise, it converts to ``ENODEV``.
+    ///
+    /// [UnsupportedArchError]: struct.UnsupportedArchError.html
+    pub fn require_arch(&self, supported: &[Arch]) -> Result<(), UnsupportedArchError> {
+        if supported.contains(&self.arch()) {
+            Ok(())
+        } else {
+            Err(UnsupportedArchError {
+                chip_name: self.chip_name().to_string(),
+                arch: self.arch(),
+                raw_arch: self.arch,
+            })
+        }
+    }
+}
+
+/// The TPCs available in each GPC, as reported by [NvHostGpuCtrl::get_tpc_masks].
+///
+/// [NvHostGpuCtrl::get_tpc_masks]: struct.NvHostGpuCtrl.html#method.get_tpc_masks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TpcMasks {
+    /// The mask of the available TPCs of each GPC.
+    masks: Vec<u32>,
+
+    /// The maximum count of TPCs per GPC, the stride of SM indices.
+    num_tpc_per_gpc: u32,
+}
+
+impl TpcMasks {
+    /// Create a new instance from the per GPC masks and the maximum count of TPCs per GPC.
+    pub fn new(masks: Vec<u32>, num_tpc_per_gpc: u32) -> Self {
+        TpcMasks {
+            masks,
+            num_tpc_per_gpc,
+        }
+    }
+
+    /// Get the mask of the TPCs available in the given GPC.
+    ///
+    /// Returns 0 if the GPC doesn't exist or is floorswept.
+    pub fn gpc_tpc_mask(&self, gpc: u32) -> u32 {
+        self.masks.get(gpc as usize).copied().unwrap_or(0)
+    }
+
+    /// Get the mask of the SMs available, an SM index being ``gpc * num_tpc_per_gpc + tpc``.
+    pub fn available_sm_mask(&self) -> u64 {
+        let mut result = 0u64;
+
+        let tpc_mask = if self.num_tpc_per_gpc >= 32 {
+            u32::MAX
+        } else {
+            (1 << self.num_tpc_per_gpc) - 1
+        };
+
+        for (gpc, mask) in self.masks.iter().enumerate() {
+            let shift = gpc as u64 * u64::from(self.num_tpc_per_gpc);
+
+            if shift >= 64 {
+                break;
+            }
+
+            result |= u64::from(mask & tpc_mask) << shift;
+        }
+
+        result
+    }
+}
+
+/// Represent the ZCULL parameters of the GPU.
+#[repr(C)]
+#[derive(Default, Debug, Clone, Copy)]
+pub struct ZcullInfo {
+    pub width_align_pixels: u32,
+    pub height_align_pixels: u32,
+    pub pixel_squares_by_aliquots: u32,
+    pub aliquot_total: u32,
+    pub region_byte_multiplier: u32,
+    pub region_header_size: u32,
+    pub subregion_header_size: u32,
+    pub subregion_width_align_pixels: u32,
+    pub subregion_height_align_pixels: u32,
+    pub subregion_count: u32,
+}
+
+/// The way the ZCULL context is stored.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ZcullMode {
+    Global,
+    NoContextSwitch,
+    SeparateBuffer,
+    PartOfRegularBuffer,
+}
+
+impl From<ZcullMode> for u32 {
+    fn from(mode: ZcullMode) -> u32 {
+        match mode {
+            ZcullMode::Global => 0,
+            ZcullMode::NoContextSwitch => 1,
+            ZcullMode::SeparateBuffer => 2,
+            ZcullMode::PartOfRegularBuffer => 3,
+        }
+    }
+}
+
+/// A ZCULL context buffer bound to a channel, see [Channel::bind_zcull_context].
+///
+/// [Channel::bind_zcull_context]: struct.Channel.html#method.bind_zcull_context
+#[derive(Debug)]
+pub struct ZcullContext {
+    /// The nvmap handle backing the context.
+    handle: Handle,
+
+    /// The address of the context in the GPU address space.
+    gpu_address: GpuVirtualAddress,
+}
+
+impl ZcullContext {
+    /// Get the nvmap handle backing the context.
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// Get the address of the context in the GPU address space.
+    pub fn gpu_address(&self) -> GpuVirtualAddress {
+        self.gpu_address
+    }
+}
+
+/// A region of an address space, see [AddressSpace::get_va_regions].
+///
+/// [AddressSpace::get_va_regions]: struct.AddressSpace.html#method.get_va_regions
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VaRegion {
+    offset: GpuVirtualAddress,
+    page_size: u32,
+    reserved: u32,
+    pages: u64,
+}
+
+impl VaRegion {
+    /// Get the start address of the region.
+    pub fn offset(&self) -> GpuVirtualAddress {
+        self.offset
+    }
+
+    /// Get the size of the pages of the region.
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    /// Get the count of pages of the region.
+    pub fn pages(&self) -> u64 {
+        self.pages
+    }
+}
+
+bitflags! {
+    /// Flags of an address space reservation, see [AddressSpace::reserve].
+    ///
+    /// [AddressSpace::reserve]: struct.AddressSpace.html#method.reserve
+    pub struct ReserveFlags: u32 {
+        /// Accesses to unmapped pages of the range are not faulting.
+        const SPARSE = 1 << 1;
     }
 }
 
 pub const KIND_DEFAULT: i32 = -1;
 
+/// Pitch linear memory kind.
+pub const KIND_PITCH: i16 = 0x0;
+
+/// Generic block linear memory kind (16Bx2).
+pub const KIND_GENERIC_16BX2: i16 = 0xFE;
+
+/// Memory kind of a surface, used when mapping a buffer with [AddressSpace::map_buffer].
+///
+/// [AddressSpace::map_buffer]: struct.AddressSpace.html#method.map_buffer
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SurfaceKind {
+    /// Pitch linear.
+    Pitch,
+
+    /// Generic block linear (16Bx2).
+    Generic16Bx2,
+
+    /// 16 bits depth.
+    Z16,
+
+    /// 16 bits depth, compressed.
+    Z16Compressed,
+
+    /// 24 bits depth with 8 bits stencil.
+    S8Z24,
+
+    /// 32 bits color, compressed.
+    C32Compressed,
+
+    /// 64 bits color, compressed.
+    C64Compressed,
+
+    /// Any other kind.
+    Other(u8),
+}
+
+impl SurfaceKind {
+    /// Check if this kind requires compression resources.
+    pub fn is_compressed(self) -> bool {
+        matches!(
+            self,
+            SurfaceKind::Z16Compressed | SurfaceKind::C32Compressed | SurfaceKind::C64Compressed
+        )
+    }
+
+    /// Get the kind to fallback to when no compression resources are available.
+    pub fn incompressible(self) -> SurfaceKind {
+        match self {
+            SurfaceKind::Z16Compressed => SurfaceKind::Z16,
+            SurfaceKind::C32Compressed | SurfaceKind::C64Compressed => SurfaceKind::Generic16Bx2,
+            kind => kind,
+        }
+    }
+
+    /// Check if this kind is a block linear one.
+    pub fn is_block_linear(self) -> bool {
+        self != SurfaceKind::Pitch
+    }
+
+    /// Get the (compressible, incompressible) kinds to map a buffer of this kind with.
+    pub fn mapping_kinds(self) -> (i16, i16) {
+        if self.is_compressed() {
+            (i16::from(self), i16::from(self.incompressible()))
+        } else {
+            (KIND_DEFAULT as i16, i16::from(self))
+        }
+    }
+}
+
+impl From<SurfaceKind> for i16 {
+    fn from(kind: SurfaceKind) -> i16 {
+        match kind {
+            SurfaceKind::Pitch => KIND_PITCH,
+            SurfaceKind::Generic16Bx2 => KIND_GENERIC_16BX2,
+            SurfaceKind::Z16 => 0x01,
+            SurfaceKind::Z16Compressed => 0x02,
+            SurfaceKind::S8Z24 => 0x11,
+            SurfaceKind::C32Compressed => 0xD8,
+            SurfaceKind::C64Compressed => 0xE6,
+            SurfaceKind::Other(value) => i16::from(value),
+        }
+    }
+}
+
+impl From<u8> for SurfaceKind {
+    fn from(value: u8) -> SurfaceKind {
+        match value {
+            0x00 => SurfaceKind::Pitch,
+            0xFE => SurfaceKind::Generic16Bx2,
+            0x01 => SurfaceKind::Z16,
+            0x02 => SurfaceKind::Z16Compressed,
+            0x11 => SurfaceKind::S8Z24,
+            0xD8 => SurfaceKind::C32Compressed,
+            0xE6 => SurfaceKind::C64Compressed,
+            value => SurfaceKind::Other(value),
+        }
+    }
+}
+
+/// The smallest page size usable with compressed kinds (the big page size).
+pub const COMPRESSION_PAGE_SIZE: u32 = 0x10000;
+
 #[allow(dead_code)]
 mod ioctl {
     use super::GpFifoRawOffset;
     use super::GpuCharacteristics;
     use super::GpuVirtualAddress;
     use super::RawFence;
+    use super::VaRegion;
+    use super::ZcullInfo;
     use std::os::unix::io::RawFd;
 
     /// NvGpuAs ioctl magic.
@@ -239,6 +967,13 @@ mod ioctl {
     /// NvGPU TSG ioctl magic.
     const NVGPU_TSG_IOCTL_MAGIC: u8 = b'T';
 
+    /// Represent the structure of ``NVGPU_GPU_IOCTL_ZCULL_GET_CTX_SIZE``.
+    #[repr(C)]
+    pub struct CtrlZcullGetContextSize {
+        /// Output.
+        pub size: u32,
+    }
+
     /// Represent the structure of ``NVGPU_GPU_IOCTL_GET_CHARACTERISTICS``.
     #[repr(C)]
     pub struct CtrlGetCharacteristics {
@@ -249,6 +984,19 @@ mod ioctl {
         pub buffer_pointer: *mut GpuCharacteristics,
     }
 
+    /// Represent the structure of ``NVGPU_GPU_IOCTL_GET_TPC_MASKS``.
+    #[repr(C)]
+    pub struct CtrlGetTpcMasks {
+        /// Input/Output (in bytes).
+        pub mask_buf_size: u32,
+
+        /// Reserved.
+        pub reserved: u32,
+
+        /// Input. Ignored if mask_buf_size is 0.
+        pub mask_buf_addr: *mut u32,
+    }
+
     /// Represent the structure of ``NVGPU_GPU_IOCTL_ALLOC_AS``.
     #[repr(C)]
     pub struct CtrlAllocAddressSpace {
@@ -285,6 +1033,13 @@ mod ioctl {
         pub reserved: u32,
     }
 
+    ioctl_read!(
+        ioc_ctrl_zcull_get_context_size,
+        NVGPU_GPU_IOCTL_MAGIC,
+        1,
+        CtrlZcullGetContextSize
+    );
+    ioctl_read!(ioc_ctrl_zcull_get_info, NVGPU_GPU_IOCTL_MAGIC, 2, ZcullInfo);
     ioctl_readwrite!(
         ioc_ctrl_get_characteristics,
         NVGPU_GPU_IOCTL_MAGIC,
@@ -298,6 +1053,12 @@ mod ioctl {
         CtrlAllocAddressSpace
     );
     ioctl_readwrite!(ioc_ctrl_open_tsg, NVGPU_GPU_IOCTL_MAGIC, 9, CtrlOpenTSG);
+    ioctl_readwrite!(
+        ioc_ctrl_get_tpc_masks,
+        NVGPU_GPU_IOCTL_MAGIC,
+        15,
+        CtrlGetTpcMasks
+    );
     ioctl_readwrite!(
         ioc_ctrl_open_channel,
         NVGPU_GPU_IOCTL_MAGIC,
@@ -347,17 +1108,96 @@ mod ioctl {
         pub offset: GpuVirtualAddress,
     }
 
-    ioctl_readwrite!(
-        ioc_as_bind_channel,
-        NVGPU_AS_IOCTL_MAGIC,
-        1,
-        BindChannelArgument
-    );
-    ioctl_readwrite!(
-        ioc_as_unmap_buffer,
-        NVGPU_AS_IOCTL_MAGIC,
-        5,
-        UnmapBufferArguments
+    /// Represent the structure of ``NVGPU_AS_IOCTL_GET_VA_REGIONS``.
+    #[repr(C)]
+    pub struct GetVaRegionsArguments {
+        /// Input. Ignored if buffer_size is 0.
+        pub buffer_address: *mut VaRegion,
+
+        /// Input/Output (in bytes).
+        pub buffer_size: u32,
+
+        /// Reserved.
+        pub reserved: u32,
+    }
+
+    /// Represent the structure of ``NVGPU_AS_IOCTL_GET_BUFFER_COMPBITS_INFO``.
+    #[repr(C)]
+    pub struct GetBufferCompbitsInfoArguments {
+        /// Input.
+        pub mapping_gva: GpuVirtualAddress,
+
+        /// Output.
+        pub compbits_win_size: u64,
+
+        /// Output.
+        pub compbits_win_ctagline: u32,
+
+        /// Output.
+        pub mapping_ctagline: u32,
+
+        /// Output.
+        pub flags: u32,
+
+        /// Reserved.
+        pub reserved: u32,
+    }
+
+    ioctl_readwrite!(
+        ioc_as_bind_channel,
+        NVGPU_AS_IOCTL_MAGIC,
+        1,
+        BindChannelArgument
+    );
+    /// Represent the structure of ``NVGPU_AS_IOCTL_ALLOC_SPACE``.
+    #[repr(C)]
+    pub struct AllocSpaceArguments {
+        /// Input.
+        pub pages: u32,
+
+        /// Input.
+        pub page_size: u32,
+
+        /// Input.
+        pub flags: u32,
+
+        /// Reserved.
+        pub padding: u32,
+
+        /// The address of the reserved range (Output) or the alignment of it (Input).
+        pub offset_or_alignment: u64,
+    }
+
+    /// Represent the structure of ``NVGPU_AS_IOCTL_FREE_SPACE``.
+    #[repr(C)]
+    pub struct FreeSpaceArguments {
+        /// Input.
+        pub offset: GpuVirtualAddress,
+
+        /// Input.
+        pub pages: u32,
+
+        /// Input.
+        pub page_size: u32,
+    }
+
+    ioctl_readwrite!(
+        ioc_as_free_space,
+        NVGPU_AS_IOCTL_MAGIC,
+        3,
+        FreeSpaceArguments
+    );
+    ioctl_readwrite!(
+        ioc_as_unmap_buffer,
+        NVGPU_AS_IOCTL_MAGIC,
+        5,
+        UnmapBufferArguments
+    );
+    ioctl_readwrite!(
+        ioc_as_alloc_space,
+        NVGPU_AS_IOCTL_MAGIC,
+        6,
+        AllocSpaceArguments
     );
     ioctl_readwrite!(
         ioc_as_map_buffer_ex,
@@ -365,6 +1205,18 @@ mod ioctl {
         7,
         MapBufferExArguments
     );
+    ioctl_readwrite!(
+        ioc_as_get_va_regions,
+        NVGPU_AS_IOCTL_MAGIC,
+        8,
+        GetVaRegionsArguments
+    );
+    ioctl_readwrite!(
+        ioc_as_get_buffer_compbits_info,
+        NVGPU_AS_IOCTL_MAGIC,
+        9,
+        GetBufferCompbitsInfoArguments
+    );
 
     /// Represent the structure of ``NVGPU_IOCTL_CHANNEL_ALLOC_GPFIFO``.
     #[repr(C)]
@@ -382,6 +1234,14 @@ mod ioctl {
         pub fence: RawFence,
     }
 
+    /// Represent the structure of ``NVGPU_IOCTL_CHANNEL_ZCULL_BIND``.
+    #[repr(C)]
+    pub struct ChannelZcullBindArguments {
+        pub gpu_va: GpuVirtualAddress,
+        pub mode: u32,
+        pub padding: u32,
+    }
+
     /// Represnet the structure of ``NVGPU_IOCTL_CHANNEL_ALLOC_OBJ_CTX``.
     #[repr(C)]
     pub struct ChannelAllocObjectContext {
@@ -408,15 +1268,60 @@ mod ioctl {
         108,
         ChannelAllocObjectContext
     );
+    ioctl_readwrite!(
+        ioc_channel_zcull_bind,
+        NVGPU_IOCTL_MAGIC,
+        110,
+        ChannelZcullBindArguments
+    );
     ioctl_none!(ioc_channel_enable, NVGPU_IOCTL_MAGIC, 113);
     ioctl_none!(ioc_channel_disable, NVGPU_IOCTL_MAGIC, 114);
 
+    /// Represent the structure of ``NVGPU_IOCTL_TSG_SET_TIMESLICE``.
+    #[repr(C)]
+    pub struct TsgTimeSliceArguments {
+        pub timeslice_us: u32,
+        pub reserved: u32,
+    }
+
     ioctl_write_ptr!(ioc_tsg_bind_channel, NVGPU_TSG_IOCTL_MAGIC, 1, RawFd);
     ioctl_write_ptr!(ioc_tsg_unbind_channel, NVGPU_TSG_IOCTL_MAGIC, 2, RawFd);
+    ioctl_write_ptr!(
+        ioc_tsg_set_timeslice,
+        NVGPU_TSG_IOCTL_MAGIC,
+        9,
+        TsgTimeSliceArguments
+    );
 }
 
 use ioctl::*;
 
+/// Call poll again as long as it is interrupted by a signal.
+///
+/// NOTE: the timeout restarts on every retry.
+fn poll_retry(fds: &mut [PollFd], timeout: i32) -> nix::Result<i32> {
+    loop {
+        match nix::poll::poll(fds, timeout) {
+            Err(Errno::EINTR) => continue,
+            res => return res,
+        }
+    }
+}
+
+/// Convert the result of an ioctl call, keeping the errno reported by the kernel.
+fn finish(res: nix::Result<i32>) -> NvGpuResult<()> {
+    match res {
+        Err(errno) => Err(errno),
+        Ok(0) => Ok(()),
+        Ok(errno) => Err(Errno::from_i32(errno)),
+    }
+}
+
+/// Convert the result of an ioctl call, producing the given value on success.
+fn finish_with<T, F: FnOnce() -> T>(res: nix::Result<i32>, value: F) -> NvGpuResult<T> {
+    finish(res).map(|_| value())
+}
+
 /// Represent an instance of `/dev/nvhost-ctrl-gpu`.
 pub struct NvHostGpuCtrl {
     /// The inner file descriptor of this instance.
@@ -448,16 +1353,28 @@ impl NvHostGpuCtrl {
         };
 
         let res = unsafe { ioc_ctrl_get_characteristics(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(result)
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        finish_with(res, || result)
+    }
+
+    /// Get the mask of the TPCs available in each GPC, floorswept TPCs are not part of it.
+    pub fn get_tpc_masks(&self) -> NvGpuResult<Vec<u32>> {
+        // First get the size of the masks buffer.
+        let mut param = CtrlGetTpcMasks {
+            mask_buf_size: 0,
+            reserved: 0,
+            mask_buf_addr: std::ptr::null_mut(),
+        };
+
+        let res = unsafe { ioc_ctrl_get_tpc_masks(self.file.as_raw_fd(), &mut param) };
+        finish(res)?;
+
+        let mut result = vec![0u32; param.mask_buf_size as usize / std::mem::size_of::<u32>()];
+
+        param.mask_buf_addr = result.as_mut_ptr();
+        param.mask_buf_size = (result.len() * std::mem::size_of::<u32>()) as u32;
+
+        let res = unsafe { ioc_ctrl_get_tpc_masks(self.file.as_raw_fd(), &mut param) };
+        finish_with(res, || result)
     }
 
     pub fn allocate_address_space(
@@ -473,16 +1390,7 @@ impl NvHostGpuCtrl {
         };
 
         let res = unsafe { ioc_ctrl_allocate_address_space(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(AddressSpace::new_from_raw_fd(param.as_fd))
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        finish_with(res, || AddressSpace::new_from_raw_fd(param.as_fd))
     }
 
     pub fn open_tsg(&self) -> NvGpuResult<TSGChannel> {
@@ -492,38 +1400,93 @@ impl NvHostGpuCtrl {
         };
 
         let res = unsafe { ioc_ctrl_open_tsg(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(TSGChannel::new_from_raw_fd(param.tsg_fd))
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        finish_with(res, || TSGChannel::new_from_raw_fd(param.tsg_fd))
     }
 
+    /// Open a channel, its GPFIFO and object context are allocated following the given configuration.
     pub fn open_channel(
         &self,
         runlist_id: i32,
         nvmap_instance: &NvMap,
         nvgpu_as: &AddressSpace,
         tsg: Option<&TSGChannel>,
+        config: &ChannelConfig,
     ) -> NvGpuResult<Channel> {
         let mut param = CtrlOpenChannel { runlist_id };
 
         let res = unsafe { ioc_ctrl_open_channel(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Channel::new_from_raw_fd(unsafe { param.channel_fd }, nvmap_instance, nvgpu_as, tsg)
-            } else {
-                Err(Errno::from_i32(errno))
-            }
+        finish(res)?;
+
+        Channel::new_from_raw_fd(
+            unsafe { param.channel_fd },
+            nvmap_instance,
+            nvgpu_as,
+            tsg,
+            config,
+        )
+    }
+
+    /// Get the size of the ZCULL context buffer to allocate for a channel.
+    pub fn get_zcull_context_size(&self) -> NvGpuResult<u32> {
+        let mut param = CtrlZcullGetContextSize { size: 0 };
+
+        let res = unsafe { ioc_ctrl_zcull_get_context_size(self.file.as_raw_fd(), &mut param) };
+        finish_with(res, || param.size)
+    }
+
+    /// Allocate an nvmap handle big enough to hold the ZCULL context of a channel.
+    ///
+    /// The size is queried with [NvHostGpuCtrl::get_zcull_context_size] and rounded up to a page.
+    ///
+    /// [NvHostGpuCtrl::get_zcull_context_size]: struct.NvHostGpuCtrl.html#method.get_zcull_context_size
+    pub fn allocate_zcull_context_buffer(&self, nvmap_instance: &NvMap) -> NvGpuResult<Handle> {
+        let size = self.get_zcull_context_size()? as usize;
+
+        if size == 0 {
+            return Err(Errno::EINVAL);
         }
+
+        let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        let handle = nvmap_instance.create(size as u64)?;
+        nvmap_instance.allocate(
+            &handle,
+            HeapMask::CARVEOUT_GENERIC,
+            AllocationFlags::uncached(),
+            PAGE_SIZE as u32,
+        )?;
+
+        Ok(handle)
+    }
+
+    /// Get the ZCULL parameters of the GPU.
+    pub fn get_zcull_info(&self) -> NvGpuResult<ZcullInfo> {
+        let mut result = ZcullInfo::default();
+
+        let res = unsafe { ioc_ctrl_zcull_get_info(self.file.as_raw_fd(), &mut result) };
+        finish_with(res, || result)
+    }
+
+    /// Check if the GPU is currently prevented from being railgated.
+    pub fn railgate_inhibited(&self) -> NvGpuResult<bool> {
+        let path = railgate_sysfs_path()?;
+        let value = std::fs::read_to_string(path).map_err(io_error_to_errno)?;
+
+        Ok(value.trim() == "0")
+    }
+
+    /// Prevent (or allow again) the GPU from being railgated when idle.
+    ///
+    /// This avoids paying the wakeup cost on the first submission of a measurement.
+    ///
+    /// NOTE: nvgpu doesn't expose this as an ioctl, this writes the global ``railgate_enable`` sysfs
+    /// attribute of the GPU device and usually requires root. ``ENOTTY`` is returned if the
+    /// attribute is not present and ``EACCES`` if it cannot be written.
+    pub fn set_railgate_inhibit(&self, inhibit: bool) -> NvGpuResult<()> {
+        let path = railgate_sysfs_path()?;
+        let value = if inhibit { "0" } else { "1" };
+
+        std::fs::write(path, value).map_err(io_error_to_errno)
     }
 
     /// Get the file descriptor used.
@@ -532,7 +1495,37 @@ impl NvHostGpuCtrl {
     }
 }
 
+impl AsRawFd for NvHostGpuCtrl {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for NvHostGpuCtrl {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+/// Known locations of the nvgpu ``railgate_enable`` sysfs attribute.
+const RAILGATE_SYSFS_PATHS: [&str; 2] = [
+    "/sys/devices/57000000.gpu/railgate_enable",
+    "/sys/devices/gpu.0/railgate_enable",
+];
+
+fn railgate_sysfs_path() -> NvGpuResult<&'static str> {
+    RAILGATE_SYSFS_PATHS
+        .iter()
+        .copied()
+        .find(|path| std::path::Path::new(path).exists())
+        .ok_or(Errno::ENOTTY)
+}
+
 /// Represent an instance of `/dev/nvhost-tsg-gpu`.
+///
+/// NOTE: a bound [Channel] keeps its own handle to the TSG and unbinds itself on drop.
+///
+/// [Channel]: struct.Channel.html
 pub struct TSGChannel {
     /// The inner file descriptor of this instance.
     file: File,
@@ -561,35 +1554,53 @@ impl TSGChannel {
     }
 
     pub fn bind_channel(&self, channel: &Channel) -> NvGpuResult<()> {
+        let tsg_file = self.file.try_clone().map_err(io_error_to_errno)?;
+
         let channel_fd = channel.as_raw_fd();
         let res = unsafe { ioc_tsg_bind_channel(self.file.as_raw_fd(), &channel_fd) };
+        finish(res)?;
 
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        *channel.bound_tsg.lock().unwrap() = Some(tsg_file);
+
+        Ok(())
     }
 
     pub fn unbind_channel(&self, channel: &Channel) -> NvGpuResult<()> {
         let channel_fd = channel.as_raw_fd();
         let res = unsafe { ioc_tsg_unbind_channel(self.file.as_raw_fd(), &channel_fd) };
+        finish(res)?;
 
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        *channel.bound_tsg.lock().unwrap() = None;
+
+        Ok(())
+    }
+
+    /// Set the timeslice of the whole TSG.
+    ///
+    /// WARNING: The timeslice of a TSG overrides the one of its channels, calling [Channel::set_priority]
+    /// on a bound channel as well is usually a mistake.
+    ///
+    /// [Channel::set_priority]: struct.Channel.html#method.set_priority
+    pub fn set_timeslice(&self, timeslice_us: u32) -> NvGpuResult<()> {
+        let param = TsgTimeSliceArguments {
+            timeslice_us,
+            reserved: 0,
+        };
+
+        let res = unsafe { ioc_tsg_set_timeslice(self.file.as_raw_fd(), &param) };
+        finish(res)
+    }
+}
+
+impl AsRawFd for TSGChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for TSGChannel {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
     }
 }
 
@@ -600,13 +1611,17 @@ impl AddressSpace {
             .read(true)
             .write(true)
             .open("/dev/nvhost-as-gpu")?;
-        Ok(AddressSpace { file })
+        Ok(AddressSpace {
+            file,
+            mappings: Mutex::new(BTreeMap::new()),
+        })
     }
 
     /// Create a new instance of NvMap from a file descriptor.
     pub fn new_from_raw_fd(raw_fd: RawFd) -> Self {
         AddressSpace {
             file: unsafe { File::from_raw_fd(raw_fd) },
+            mappings: Mutex::new(BTreeMap::new()),
         }
     }
 
@@ -620,26 +1635,43 @@ impl AddressSpace {
         let mut param = BindChannelArgument { channel_fd };
 
         let res = unsafe { ioc_as_bind_channel(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        finish(res)
     }
 
+    /// Map a buffer in this address space with the given kind.
+    ///
+    /// ``None`` maps the buffer as pitch linear without compression. Compressed kinds require big pages,
+    /// ``EINVAL`` is returned if ``page_size`` is smaller than [COMPRESSION_PAGE_SIZE].
+    /// The kernel falls back to the incompressible equivalent of the kind if no compression resources are left.
+    ///
+    /// [COMPRESSION_PAGE_SIZE]: constant.COMPRESSION_PAGE_SIZE.html
     pub fn map_buffer(
         &self,
         handle: &Handle,
         flags: u32,
         page_size: u32,
         fixed_address: GpuVirtualAddress,
+        kind: Option<SurfaceKind>,
     ) -> NvGpuResult<GpuVirtualAddress> {
-        self.map_buffer_extended(handle.fd, flags, 0, 0, page_size, 0, 0, fixed_address)
+        let (compr_kind, incompr_kind) = match kind {
+            None => (KIND_DEFAULT as i16, KIND_PITCH),
+            Some(kind) if kind.is_compressed() && page_size < COMPRESSION_PAGE_SIZE => {
+                return Err(Errno::EINVAL);
+            }
+            Some(kind) => kind.mapping_kinds(),
+        };
+
+        self.map_buffer_extended(
+            handle.fd,
+            flags,
+            compr_kind,
+            incompr_kind,
+            page_size,
+            0,
+            0,
+            fixed_address,
+        )
+        .map(|mapping| mapping.address)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -653,7 +1685,7 @@ impl AddressSpace {
         buffer_offset: u64,
         mapping_size: u64,
         fixed_address: GpuVirtualAddress,
-    ) -> NvGpuResult<GpuVirtualAddress> {
+    ) -> NvGpuResult<Mapping> {
         let mut param = MapBufferExArguments {
             flags: flags | (1 << 8),
             compr_kind,
@@ -666,33 +1698,157 @@ impl AddressSpace {
         };
 
         let res = unsafe { ioc_as_map_buffer_ex(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(param.offset)
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        finish(res)?;
+
+        self.mappings
+            .lock()
+            .unwrap()
+            .insert(param.offset, param.mapping_size);
+
+        Ok(Mapping {
+            address: param.offset,
+            size: param.mapping_size,
+        })
+    }
+
+    /// Get the compression bits information of the buffer mapped at the given address.
+    ///
+    /// NOTE: Only buffers mapped with a compressible kind have compression bits.
+    /// ``ENOTTY`` is returned if the kernel doesn't support compression.
+    pub fn get_compbits_info(&self, address: GpuVirtualAddress) -> NvGpuResult<CompbitsInfo> {
+        let mut param = GetBufferCompbitsInfoArguments {
+            mapping_gva: address,
+            compbits_win_size: 0,
+            compbits_win_ctagline: 0,
+            mapping_ctagline: 0,
+            flags: 0,
+            reserved: 0,
+        };
+
+        let res = unsafe { ioc_as_get_buffer_compbits_info(self.file.as_raw_fd(), &mut param) };
+        finish(res)?;
+
+        Ok(CompbitsInfo {
+            window_size: param.compbits_win_size,
+            window_ctagline: param.compbits_win_ctagline,
+            mapping_ctagline: param.mapping_ctagline,
+            flags: CompbitsFlags::from_bits_truncate(param.flags),
+        })
     }
 
     pub fn unmap_buffer(&self, address: GpuVirtualAddress) -> NvGpuResult<()> {
         let mut param = UnmapBufferArguments { offset: address };
 
         let res = unsafe { ioc_as_unmap_buffer(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
-            }
+        finish(res)?;
+
+        self.mappings.lock().unwrap().remove(&address);
+
+        Ok(())
+    }
+
+    /// Get the regions of this address space and their page size.
+    pub fn get_va_regions(&self) -> NvGpuResult<Vec<VaRegion>> {
+        // First get the size of the regions buffer.
+        let mut param = GetVaRegionsArguments {
+            buffer_address: std::ptr::null_mut(),
+            buffer_size: 0,
+            reserved: 0,
+        };
+
+        let res = unsafe { ioc_as_get_va_regions(self.file.as_raw_fd(), &mut param) };
+        finish(res)?;
+
+        let count = param.buffer_size as usize / std::mem::size_of::<VaRegion>();
+        let mut result = vec![VaRegion::default(); count];
+
+        param.buffer_address = result.as_mut_ptr();
+        param.buffer_size = (count * std::mem::size_of::<VaRegion>()) as u32;
+
+        let res = unsafe { ioc_as_get_va_regions(self.file.as_raw_fd(), &mut param) };
+        finish(res)?;
+
+        // The regions could have changed between the two calls.
+        result.truncate(param.buffer_size as usize / std::mem::size_of::<VaRegion>());
+
+        Ok(result)
+    }
+
+    /// Reserve a range of the given count of pages in this address space, buffers can then be mapped in it at a fixed address.
+    ///
+    /// ``flags`` are the bits of [ReserveFlags].
+    ///
+    /// [ReserveFlags]: struct.ReserveFlags.html
+    pub fn reserve(
+        &self,
+        pages: u32,
+        page_size: u32,
+        flags: u32,
+    ) -> NvGpuResult<GpuVirtualAddress> {
+        let mut param = AllocSpaceArguments {
+            pages,
+            page_size,
+            flags,
+            padding: 0,
+            offset_or_alignment: 0,
+        };
+
+        let res = unsafe { ioc_as_alloc_space(self.file.as_raw_fd(), &mut param) };
+        finish_with(res, || param.offset_or_alignment)
+    }
+
+    /// Free a range previously reserved with [AddressSpace::reserve].
+    ///
+    /// [AddressSpace::reserve]: struct.AddressSpace.html#method.reserve
+    pub fn free_reserved(
+        &self,
+        address: GpuVirtualAddress,
+        pages: u32,
+        page_size: u32,
+    ) -> NvGpuResult<()> {
+        let mut param = FreeSpaceArguments {
+            offset: address,
+            pages,
+            page_size,
+        };
+
+        let res = unsafe { ioc_as_free_space(self.file.as_raw_fd(), &mut param) };
+        finish(res)
+    }
+
+    /// Check if the given address is part of a buffer mapped in this address space.
+    ///
+    /// NOTE: if the kernel didn't report the size of a mapping, only its base address is considered mapped.
+    pub fn is_mapped(&self, va: GpuVirtualAddress) -> bool {
+        let mappings = self.mappings.lock().unwrap();
+
+        match mappings.range(..=va).next_back() {
+            Some((address, size)) => va == *address || va - *address < *size,
+            None => false,
         }
     }
+
+    /// Get the buffers currently mapped in this address space as (address, size) pairs.
+    pub fn mappings(&self) -> Vec<(GpuVirtualAddress, u64)> {
+        self.mappings
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(address, size)| (*address, *size))
+            .collect()
+    }
+}
+
+impl AsRawFd for AddressSpace {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for AddressSpace {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
 }
 
 impl Channel {
@@ -705,28 +1861,64 @@ impl Channel {
         path: &str,
         nvmap_instance: &NvMap,
         nvgpu_as: &AddressSpace,
+    ) -> NvGpuResult<Self> {
+        Self::with_config_from_path(path, nvmap_instance, nvgpu_as, &ChannelConfig::default())
+    }
+
+    /// Create a new instance of Channel with the given configuration by opening `/dev/nvhost-gpu`.
+    pub fn with_config(
+        nvmap_instance: &NvMap,
+        nvgpu_as: &AddressSpace,
+        config: &ChannelConfig,
+    ) -> NvGpuResult<Self> {
+        Self::with_config_from_path("/dev/nvhost-gpu", nvmap_instance, nvgpu_as, config)
+    }
+
+    /// Create a new instance of Channel for compute only workloads by opening `/dev/nvhost-gpu`.
+    ///
+    /// See [ChannelConfig::compute], use [GpuContext::open_with_config] to get a compute only channel bound to a TSG.
+    ///
+    /// [ChannelConfig::compute]: struct.ChannelConfig.html#method.compute
+    /// [GpuContext::open_with_config]: struct.GpuContext.html#method.open_with_config
+    pub fn new_compute(nvmap_instance: &NvMap, nvgpu_as: &AddressSpace) -> NvGpuResult<Self> {
+        Self::with_config(nvmap_instance, nvgpu_as, &ChannelConfig::compute())
+    }
+
+    /// Open a channel bound to the address space with its GPFIFO and object context allocated following the given configuration.
+    fn with_config_from_path(
+        path: &str,
+        nvmap_instance: &NvMap,
+        nvgpu_as: &AddressSpace,
+        config: &ChannelConfig,
     ) -> NvGpuResult<Self> {
         let nvhost_channel =
             NvHostChannel::new(path, nvmap_instance).expect("Cannot open GPU channel");
         let mut channel = Channel {
             inner: nvhost_channel,
+            object_contexts: Vec::new(),
+            bound_tsg: Mutex::new(None),
+            is_clone: false,
         };
         nvgpu_as.bind_channel(&channel)?;
-        channel.allocate_gpfifo(GPFIFO_QUEUE_SIZE, 0)?;
-        channel.allocate_object_context(ClassId::MAXWELL_B_3D, 0x0)?;
+        channel.allocate_gpfifo(config.gpfifo_entries, 0)?;
+        channel.allocate_object_context(config.object_class, config.flags)?;
         Ok(channel)
     }
 
-    /// Create a new instance of NvMap from a file descriptor.
+    /// Create a new instance of Channel from a file descriptor, its GPFIFO and object context are allocated following the given configuration.
     pub fn new_from_raw_fd(
         raw_fd: RawFd,
         nvmap_instance: &NvMap,
         nvgpu_as: &AddressSpace,
         tsg: Option<&TSGChannel>,
+        config: &ChannelConfig,
     ) -> NvGpuResult<Self> {
         let nvhost_channel = NvHostChannel::new_from_raw_fd(raw_fd, nvmap_instance)?;
         let mut channel = Channel {
             inner: nvhost_channel,
+            object_contexts: Vec::new(),
+            bound_tsg: Mutex::new(None),
+            is_clone: false,
         };
 
         if let Some(tsg) = tsg {
@@ -736,15 +1928,90 @@ impl Channel {
         }
 
         nvgpu_as.bind_channel(&channel)?;
-        channel.allocate_gpfifo(GPFIFO_QUEUE_SIZE, 0)?;
-        channel.allocate_object_context(ClassId::MAXWELL_B_3D, 0x0)?;
+        channel.allocate_gpfifo(config.gpfifo_entries, 0)?;
+        channel.allocate_object_context(config.object_class, config.flags)?;
         Ok(channel)
     }
 
+    /// Bind a ZCULL context buffer to this channel.
+    ///
+    /// The buffer must be mapped at ``gpu_va`` and be at least [NvHostGpuCtrl::get_zcull_context_size] bytes,
+    /// page aligned.
+    /// ``ENOTTY`` is returned if ZCULL isn't available.
+    ///
+    /// [NvHostGpuCtrl::get_zcull_context_size]: struct.NvHostGpuCtrl.html#method.get_zcull_context_size
+    pub fn bind_zcull_buffer(&self, gpu_va: GpuVirtualAddress, mode: ZcullMode) -> NvGpuResult<()> {
+        let mut param = ChannelZcullBindArguments {
+            gpu_va,
+            mode: u32::from(mode),
+            padding: 0,
+        };
+
+        let res = unsafe { ioc_channel_zcull_bind(self.inner.as_raw_fd(), &mut param) };
+        finish(res)
+    }
+
+    /// Allocate a ZCULL context buffer, map it in the given address space and bind it to this channel.
+    ///
+    /// The returned [ZcullContext] must be kept alive as long as the channel is used.
+    ///
+    /// [ZcullContext]: struct.ZcullContext.html
+    pub fn bind_zcull_context(
+        &self,
+        ctrl: &NvHostGpuCtrl,
+        nvmap_instance: &NvMap,
+        nvgpu_as: &AddressSpace,
+        mode: ZcullMode,
+    ) -> NvGpuResult<ZcullContext> {
+        let handle = ctrl.allocate_zcull_context_buffer(nvmap_instance)?;
+        let gpu_address = nvgpu_as.map_buffer(&handle, 0, PAGE_SIZE as u32, 0, None)?;
+
+        self.bind_zcull_buffer(gpu_address, mode)?;
+
+        Ok(ZcullContext {
+            handle,
+            gpu_address,
+        })
+    }
+
+    /// Duplicate the file descriptor of this channel.
+    ///
+    /// The clone refers to the same kernel channel (and nvmap association).
+    ///
+    /// NOTE: Submitting concurrently from clones requires external synchronization of the GPFIFO ring.
+    /// Only the original instance disables and unbinds the channel on drop.
+    pub fn try_clone(&self) -> NvGpuResult<Self> {
+        Ok(Channel {
+            inner: self.inner.try_clone()?,
+            object_contexts: self.object_contexts.clone(),
+            bound_tsg: Mutex::new(None),
+            is_clone: true,
+        })
+    }
+
     pub fn set_priority(&self, priority: ChannelPriority) -> NvGpuResult<()> {
         self.inner.set_priority(priority)
     }
 
+    /// Set the timeout of submissions on this channel.
+    pub fn set_timeout(&self, timeout_ms: u32) -> NvGpuResult<()> {
+        self.inner.set_timeout(timeout_ms)
+    }
+
+    /// Set the timeout of submissions on this channel with the given flags.
+    pub fn set_timeout_ex(&self, timeout_ms: u32, flags: u32) -> NvGpuResult<()> {
+        self.inner.set_timeout_ex(timeout_ms, flags)
+    }
+
+    /// Make the kernel report channel errors to the given range of a memory handle.
+    ///
+    /// See [NvHostChannel::set_error_notifier].
+    ///
+    /// [NvHostChannel::set_error_notifier]: ../nvhost/struct.NvHostChannel.html#method.set_error_notifier
+    pub fn set_error_notifier(&self, handle: &Handle, offset: u64, size: u64) -> NvGpuResult<()> {
+        self.inner.set_error_notifier(handle, offset, size)
+    }
+
     pub fn allocate_gpfifo(&mut self, gpfifo_queue_size: usize, flags: u32) -> NvGpuResult<()> {
         let param = ChannelAllocGpFifoArguments {
             num_entries: gpfifo_queue_size as u32,
@@ -752,25 +2019,25 @@ impl Channel {
         };
 
         let res = unsafe { ioc_channel_alloc_gpfifo(self.inner.as_raw_fd(), &param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        finish(res)
     }
 
     pub fn submit_gpfifo(
         &self,
         entries: &[GpFifoRawOffset],
-        input_fence: Option<RawFence>,
+        input_fence: Option<Fence>,
         flags: u32,
-    ) -> NvGpuResult<Option<RawFence>> {
-        let input_fence = input_fence.unwrap_or_else(|| RawFence {
+    ) -> NvGpuResult<Option<OutputFence>> {
+        let submit_flags = SubmitFlags::from_bits_truncate(flags);
+
+        // The kind of the input fence must match the fence representation requested.
+        if let Some(fence) = input_fence {
+            if fence.is_fd() != submit_flags.contains(SubmitFlags::SYNC_FENCE) {
+                return Err(Errno::EINVAL);
+            }
+        }
+
+        let input_fence = input_fence.map(Fence::to_raw).unwrap_or_else(|| RawFence {
             id: -1,
             value: 0xFFFF_FFFF,
         });
@@ -783,24 +2050,54 @@ impl Channel {
         };
 
         let res = unsafe { ioc_channel_submit_gpfifo(self.inner.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
+        finish(res)?;
+
+        let output_fence = if submit_flags.contains(SubmitFlags::FENCE_GET) {
+            Some(OutputFence::new(Fence::from_raw(param.fence, submit_flags)))
         } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                let output_fence = if flags & (1 << 1) != 0 {
-                    Some(param.fence)
-                } else {
-                    None
-                };
-                Ok(output_fence)
-            } else {
-                Err(Errno::from_i32(errno))
-            }
+            None
+        };
+
+        Ok(output_fence)
+    }
+
+    /// Submit entries and return the completion fence as a sync_file file descriptor.
+    ///
+    /// ``wait`` is an optional sync_file file descriptor to wait on before executing the entries.
+    ///
+    /// The caller owns the returned file descriptor and must close it.
+    ///
+    /// Returns ``EINVAL`` if the kernel doesn't return a sync_file.
+    pub fn submit_gpfifo_fd(
+        &self,
+        entries: &[GpFifoRawOffset],
+        wait: Option<RawFd>,
+    ) -> NvGpuResult<RawFd> {
+        let mut flags = SubmitFlags::FENCE_GET | SubmitFlags::SYNC_FENCE;
+
+        let input_fence = wait.map(|fd| {
+            flags |= SubmitFlags::FENCE_WAIT;
+
+            Fence::Fd(fd)
+        });
+
+        let output_fence = self.submit_gpfifo(entries, input_fence, flags.bits())?;
+
+        // FENCE_GET and SYNC_FENCE are set so the kernel should always return a sync_file.
+        match output_fence.map(OutputFence::into_fence) {
+            Some(Fence::Fd(fd)) => Ok(fd),
+            _ => Err(Errno::EINVAL),
         }
     }
 
+    /// Allocate an object context of the given class on this channel.
+    ///
+    /// If an object context of this class was already allocated, its object id is returned.
     pub fn allocate_object_context(&mut self, class_num: ClassId, flags: u32) -> NvGpuResult<u64> {
+        if let Some(obj_id) = self.object_context(class_num) {
+            return Ok(obj_id);
+        }
+
         let mut param = ChannelAllocObjectContext {
             class_num: u32::from(class_num),
             flags,
@@ -808,44 +2105,59 @@ impl Channel {
         };
 
         let res = unsafe { ioc_channel_alloc_object_context(self.inner.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(param.obj_id)
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        finish(res)?;
+
+        self.object_contexts.push((class_num, param.obj_id));
+
+        Ok(param.obj_id)
+    }
+
+    /// Allocate an object context for each of the given classes, returning their object ids.
+    pub fn allocate_contexts(&mut self, classes: &[ClassId]) -> NvGpuResult<Vec<u64>> {
+        classes
+            .iter()
+            .map(|class_num| self.allocate_object_context(*class_num, 0))
+            .collect()
+    }
+
+    /// Get the object id of the object context of the given class if allocated.
+    pub fn object_context(&self, class_num: ClassId) -> Option<u64> {
+        self.object_contexts
+            .iter()
+            .find(|(class, _)| *class == class_num)
+            .map(|(_, obj_id)| *obj_id)
     }
 
     pub fn enable(&self) -> NvGpuResult<()> {
         let res = unsafe { ioc_channel_enable(self.inner.as_raw_fd()) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        finish(res)
     }
 
     pub fn disable(&self) -> NvGpuResult<()> {
         let res = unsafe { ioc_channel_disable(self.inner.as_raw_fd()) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        finish(res)
+    }
+
+    /// Wait for all the work submitted on this channel to be complete.
+    ///
+    /// Unlike [GpFifoQueue::wait_idle] that only waits on the fence of the last submission,
+    /// this waits on the maximum value of the channel syncpoint, covering the submissions of every clone of this channel.
+    ///
+    /// NOTE: nvgpu channels don't implement the nvhost syncpoint query, the syncpoint is taken from the fence
+    /// of an empty submission.
+    ///
+    /// [GpFifoQueue::wait_idle]: struct.GpFifoQueue.html#method.wait_idle
+    pub fn wait_all_idle(&self, ctrl: &NvHostCtrl) -> NvGpuResult<()> {
+        let fence = self.submit_gpfifo(&[], None, SubmitFlags::FENCE_GET.bits())?;
+
+        let syncpoint_id = match fence.map(OutputFence::into_fence) {
+            Some(Fence::Syncpoint { id, .. }) => id,
+            _ => return Err(Errno::EINVAL),
+        };
+
+        let threshold = ctrl.read_syncpoint_max(syncpoint_id)?;
+
+        ctrl.wait_syncpoint(syncpoint_id, threshold, -1)
     }
 
     /// Get the file descriptor used.
@@ -853,3 +2165,381 @@ impl Channel {
         self.inner.as_raw_fd()
     }
 }
+
+impl AsRawFd for Channel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for Channel {
+    fn into_raw_fd(mut self) -> RawFd {
+        let fd = self.inner.as_raw_fd();
+
+        // The channel stays enabled and bound, keep the file descriptor open.
+        drop(std::mem::take(&mut self.object_contexts));
+        drop(self.bound_tsg.get_mut().unwrap().take());
+        std::mem::forget(self);
+
+        fd
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        if !self.is_clone {
+            let _ = self.disable();
+
+            if let Some(tsg_file) = self.bound_tsg.get_mut().unwrap().take() {
+                let channel_fd = self.inner.as_raw_fd();
+                let _ = unsafe { ioc_tsg_unbind_channel(tsg_file.as_raw_fd(), &channel_fd) };
+            }
+        }
+    }
+}
+
+/// Represent all the resources needed to submit work to the GPU.
+///
+/// NOTE: fields are declared in drop order, the channel must be dropped before its address space.
+pub struct GpuContext {
+    /// The channel used for submissions.
+    channel: Channel,
+
+    /// The TSG the channel is bound to.
+    tsg: TSGChannel,
+
+    /// The address space the channel is bound to.
+    address_space: AddressSpace,
+
+    /// The nvmap instance used for allocations.
+    nvmap: NvMap,
+
+    /// The GPU control instance.
+    ctrl: NvHostGpuCtrl,
+
+    /// The nvhost control instance, used to wait on syncpoints.
+    host_ctrl: NvHostCtrl,
+
+    /// The characteristics of the GPU.
+    characteristics: GpuCharacteristics,
+
+    /// The TPCs available in each GPC.
+    tpc_masks: TpcMasks,
+}
+
+/// Convert an I/O error to an errno.
+fn io_error_to_errno(error: std::io::Error) -> Errno {
+    Errno::from_i32(error.raw_os_error().unwrap_or(0))
+}
+
+impl GpuContext {
+    /// Open all the resources needed to submit work to the GPU.
+    pub fn open() -> NvGpuResult<Self> {
+        Self::open_with_config(&ChannelConfig::default())
+    }
+
+    /// Open all the resources needed to submit work to the GPU, the channel is configured with the given configuration.
+    ///
+    /// Use [ChannelConfig::compute] to get a compute only channel.
+    ///
+    /// [ChannelConfig::compute]: struct.ChannelConfig.html#method.compute
+    pub fn open_with_config(config: &ChannelConfig) -> NvGpuResult<Self> {
+        let ctrl = NvHostGpuCtrl::new().map_err(io_error_to_errno)?;
+        let host_ctrl = NvHostCtrl::new().map_err(io_error_to_errno)?;
+        let nvmap = NvMap::new().map_err(io_error_to_errno)?;
+        let address_space = ctrl.allocate_address_space(0x10000, 0)?;
+        let tsg = ctrl.open_tsg()?;
+        let channel = ctrl.open_channel(-1, &nvmap, &address_space, Some(&tsg), config)?;
+        let characteristics = ctrl.get_characteristics()?;
+        let tpc_masks = TpcMasks::new(ctrl.get_tpc_masks()?, characteristics.num_tpc_per_gpc);
+
+        Ok(GpuContext {
+            channel,
+            tsg,
+            address_space,
+            nvmap,
+            ctrl,
+            host_ctrl,
+            characteristics,
+            tpc_masks,
+        })
+    }
+
+    /// Get the channel used for submissions.
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    /// Get the channel used for submissions.
+    pub fn channel_mut(&mut self) -> &mut Channel {
+        &mut self.channel
+    }
+
+    /// Get the TSG the channel is bound to.
+    pub fn tsg(&self) -> &TSGChannel {
+        &self.tsg
+    }
+
+    /// Get the address space the channel is bound to.
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    /// Get the nvmap instance.
+    pub fn nvmap(&self) -> &NvMap {
+        &self.nvmap
+    }
+
+    /// Get the GPU control instance.
+    pub fn ctrl(&self) -> &NvHostGpuCtrl {
+        &self.ctrl
+    }
+
+    /// Get the nvhost control instance, used to wait on syncpoints.
+    pub fn host_ctrl(&self) -> &NvHostCtrl {
+        &self.host_ctrl
+    }
+
+    /// Get the characteristics of the GPU.
+    pub fn characteristics(&self) -> &GpuCharacteristics {
+        &self.characteristics
+    }
+
+    /// Get the TPCs available in each GPC.
+    pub fn tpc_masks(&self) -> &TpcMasks {
+        &self.tpc_masks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn require_arch_reports_the_detected_chip() {
+        let characteristics = GpuCharacteristics {
+            arch: 0x130,
+            gpu_impl: 0xB,
+            ..GpuCharacteristics::default()
+        };
+
+        let error = characteristics.require_arch(&[Arch::Maxwell]).unwrap_err();
+
+        assert_eq!(
+            error,
+            UnsupportedArchError {
+                chip_name: String::from("gp10b"),
+                arch: Arch::Pascal,
+                raw_arch: 0x130,
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "unsupported chip \"gp10b\" (Pascal, arch 0x130)"
+        );
+        assert_eq!(Errno::from(error), Errno::ENODEV);
+
+        let characteristics = GpuCharacteristics {
+            arch: 0x120,
+            gpu_impl: 0xB,
+            ..GpuCharacteristics::default()
+        };
+
+        assert!(characteristics
+            .require_arch(&[Arch::Maxwell, Arch::Pascal])
+            .is_ok());
+    }
+
+    #[test]
+    fn tpc_masks_report_floorswept_tpcs() {
+        // The second TPC of the second GPC is floorswept.
+        let tpc_masks = TpcMasks::new(vec![0b11, 0b01], 2);
+
+        assert_eq!(tpc_masks.gpc_tpc_mask(0), 0b11);
+        assert_eq!(tpc_masks.gpc_tpc_mask(1), 0b01);
+        assert_eq!(tpc_masks.gpc_tpc_mask(2), 0);
+        assert_eq!(tpc_masks.available_sm_mask(), 0b0111);
+
+        // Bits over the TPC count of a GPC are ignored.
+        assert_eq!(TpcMasks::new(vec![0b111], 2).available_sm_mask(), 0b11);
+        assert_eq!(TpcMasks::default().available_sm_mask(), 0);
+    }
+
+    #[test]
+    fn surface_kind_mapping_kinds() {
+        assert_eq!(SurfaceKind::Pitch.mapping_kinds(), (-1, KIND_PITCH));
+        assert_eq!(
+            SurfaceKind::Generic16Bx2.mapping_kinds(),
+            (-1, KIND_GENERIC_16BX2)
+        );
+        assert_eq!(
+            SurfaceKind::C32Compressed.mapping_kinds(),
+            (0xD8, KIND_GENERIC_16BX2)
+        );
+        assert!(!SurfaceKind::Pitch.is_block_linear());
+        assert!(SurfaceKind::from(0xFE).is_block_linear());
+    }
+
+    #[test]
+    fn gpfifo_entry_packing() {
+        let address = 0x1_2345_6000;
+
+        assert_eq!(
+            pack_gpfifo_entry(address, 0x10, GpFifoAppendFlags::empty()),
+            0x0000_4001_2345_6000
+        );
+        assert_eq!(
+            pack_gpfifo_entry(address, 0x10, GpFifoAppendFlags::NO_PREFETCH),
+            0x8000_4001_2345_6000
+        );
+        assert_eq!(
+            pack_gpfifo_entry(
+                address,
+                0x10,
+                GpFifoAppendFlags::PRIVILEGED | GpFifoAppendFlags::SUBROUTINE
+            ),
+            0x0000_4301_2345_6000
+        );
+        assert_eq!(
+            pack_gpfifo_entry(
+                address,
+                GPFIFO_ENTRY_MAX_WORDS as u64,
+                GpFifoAppendFlags::all()
+            ),
+            0xFFFF_FF01_2345_6001
+        );
+    }
+
+    #[test]
+    fn gpfifo_entry_inline_decode() {
+        // Inline mode, sub channel 1, method 0x6C with 0x11 inlined.
+        let entry = GpFifoEntry(0x8011_206C);
+
+        assert!(entry.is_inline());
+        assert_eq!(entry.method(), 0x6C);
+        assert_eq!(entry.sub_channel(), 1);
+        assert_eq!(entry.inline_arguments(), 0x11);
+        assert_eq!(entry.effective_argument_count(), 1);
+    }
+
+    #[test]
+    fn gpfifo_entry_non_inline_decode() {
+        // Increasing mode, sub channel 4, method 0xC0 with 3 arguments.
+        let entry = GpFifoEntry(0x2003_80C0);
+
+        assert!(!entry.is_inline());
+        assert_eq!(entry.method(), 0xC0);
+        assert_eq!(entry.sub_channel(), 4);
+        assert_eq!(entry.argument_count(), 3);
+        assert_eq!(entry.effective_argument_count(), 3);
+
+        // An empty increasing command has no argument at all.
+        assert_eq!(GpFifoEntry(0x2000_0000).effective_argument_count(), 0);
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device"]
+    fn channel_is_disabled_and_unbound_on_drop() {
+        let ctrl = NvHostGpuCtrl::new().unwrap();
+        let nvmap = NvMap::new().unwrap();
+        let address_space = ctrl.allocate_address_space(0x10000, 0).unwrap();
+        let tsg = ctrl.open_tsg().unwrap();
+        let channel = ctrl
+            .open_channel(
+                -1,
+                &nvmap,
+                &address_space,
+                Some(&tsg),
+                &ChannelConfig::default(),
+            )
+            .unwrap();
+
+        // The clone keeps the kernel channel alive after the original is dropped.
+        let clone = channel.try_clone().unwrap();
+        drop(channel);
+
+        assert!(tsg.unbind_channel(&clone).is_err());
+
+        // A disabled channel doesn't process submissions.
+        let flags = SubmitFlags::FENCE_GET | SubmitFlags::SYNC_FENCE;
+        let fence = clone
+            .submit_gpfifo(&[], None, flags.bits())
+            .unwrap()
+            .unwrap();
+
+        let host_ctrl = NvHostCtrl::new().unwrap();
+
+        assert!(!fence
+            .fence()
+            .wait(&host_ctrl, Some(Duration::from_millis(100)))
+            .unwrap());
+    }
+
+    #[test]
+    fn fence_wait_on_sync_fd() {
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+
+        // Only the readiness of the file descriptor matters, a pipe stands in for a sync_file.
+        let _fence = OutputFence::new(Fence::Fd(read_end));
+
+        assert!(!wait_sync_fd(read_end, 0).unwrap());
+
+        nix::unistd::write(write_end, &[1]).unwrap();
+
+        assert!(wait_sync_fd(read_end, -1).unwrap());
+
+        nix::unistd::close(write_end).unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires nvhost and a VIC channel"]
+    fn fence_wait_on_syncpoint() {
+        let host_ctrl = NvHostCtrl::new().unwrap();
+        let nvmap = NvMap::new().unwrap();
+        let host_channel = NvHostChannel::new("/dev/nvhost-vic", &nvmap).unwrap();
+        let id = host_channel
+            .allocate_client_managed_syncpoint("fence_wait_test")
+            .unwrap();
+        let value = host_ctrl.read_syncpoint(id).unwrap() + 1;
+
+        let fence = Fence::Syncpoint { id, value };
+
+        assert!(!fence
+            .wait(&host_ctrl, Some(Duration::from_millis(10)))
+            .unwrap());
+
+        host_ctrl.increment_syncpoint(id).unwrap();
+
+        assert!(fence.wait(&host_ctrl, None).unwrap());
+    }
+
+    #[test]
+    #[ignore = "requires an nvgpu device and a VIC channel"]
+    fn submit_with_timeout_expires_on_blocked_work() {
+        let context = GpuContext::open().unwrap();
+        let host_ctrl = context.host_ctrl();
+        let host_channel = NvHostChannel::new("/dev/nvhost-vic", context.nvmap()).unwrap();
+        let id = host_channel
+            .allocate_client_managed_syncpoint("submit_timeout_test")
+            .unwrap();
+        let value = host_ctrl.read_syncpoint(id).unwrap() + 1;
+        let mut queue = GpFifoQueue::new(context.channel(), host_ctrl);
+
+        // Nothing blocks this submission.
+        queue.submit_with_timeout(Duration::from_secs(1)).unwrap();
+
+        // This one waits on a syncpoint only the CPU increments.
+        queue.wait_on(RawFence { id, value }).unwrap();
+
+        assert_eq!(
+            queue.submit_with_timeout(Duration::from_millis(10)),
+            Err(Errno::ETIMEDOUT)
+        );
+
+        host_ctrl.increment_syncpoint(id).unwrap();
+
+        assert!(queue
+            .wait_idle_timeout(Some(Duration::from_secs(1)))
+            .unwrap());
+    }
+}
@@ -2,14 +2,21 @@
 #[macro_use]
 extern crate nix;
 
+use bitflags::bitflags;
+
 use nix::errno::Errno;
+use nvmap::Handle;
 use nvmap::NvMap;
 
+use std::convert::TryFrom;
+use std::ffi::CString;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::FromRawFd;
+use std::os::unix::io::IntoRawFd;
 use std::os::unix::io::RawFd;
+use std::time::Duration;
 
 /// Represent a SyncPoint identifier.
 pub type SyncPointId = i32;
@@ -34,6 +41,25 @@ pub struct NvHostChannel {
     file: File,
 }
 
+/// Represent a sync_file fence created by [NvHostCtrl::create_fence], the file descriptor is closed on drop.
+///
+/// [NvHostCtrl::create_fence]: struct.NvHostCtrl.html#method.create_fence
+#[derive(Debug)]
+pub struct SyncFence {
+    /// The inner file descriptor of this fence.
+    fd: RawFd,
+}
+
+/// A locked hardware module mutex, unlocked on drop.
+///
+/// See [NvHostCtrl::lock_module_mutex].
+///
+/// [NvHostCtrl::lock_module_mutex]: struct.NvHostCtrl.html#method.lock_module_mutex
+pub struct ModuleMutexGuard<'a> {
+    ctrl: &'a NvHostCtrl,
+    id: SyncPointId,
+}
+
 /// The result of NvHost operations.
 pub type NvHostResult<T> = std::result::Result<T, Errno>;
 
@@ -43,7 +69,11 @@ pub struct SyncFenceInfo {
     threshhold: u32,
 }
 
+/// Characteristics of nvhost, see [NvHostCtrl::get_characteristics].
+///
+/// [NvHostCtrl::get_characteristics]: struct.NvHostCtrl.html#method.get_characteristics
 #[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
 pub struct Characteristics {
     flags: u64,
     num_mlocks: u32,
@@ -54,6 +84,56 @@ pub struct Characteristics {
     padding: u32,
 }
 
+bitflags! {
+    /// Capabilities of nvhost reported in [Characteristics::capabilities]
+    ///
+    /// [Characteristics::capabilities]: struct.Characteristics.html#method.capabilities
+    pub struct HostCapabilities: u64 {
+        /// Gather filter is supported.
+        const GATHER_FILTER = 1 << 0;
+
+        /// Resources (syncpoints, mutexes) are allocated per channel instance.
+        const RESOURCE_PER_CHANNEL_INSTANCE = 1 << 1;
+
+        /// Pre-fences are supported on submission.
+        const SUPPORT_PREFENCES = 1 << 2;
+    }
+}
+
+impl Characteristics {
+    /// Get the capabilities reported by nvhost.
+    ///
+    /// NOTE: unknown bits are preserved.
+    pub fn capabilities(&self) -> HostCapabilities {
+        unsafe { HostCapabilities::from_bits_unchecked(self.flags) }
+    }
+
+    /// Get the count of hardware module mutexes.
+    pub fn num_mlocks(&self) -> u32 {
+        self.num_mlocks
+    }
+
+    /// Get the count of syncpoints.
+    pub fn num_syncpts(&self) -> u32 {
+        self.num_syncpts
+    }
+
+    /// Get the first syncpoint usable by clients.
+    pub fn syncpts_base(&self) -> u32 {
+        self.syncpts_base
+    }
+
+    /// Get the end of the syncpoint range usable by clients.
+    pub fn syncpts_limit(&self) -> u32 {
+        self.syncpts_limit
+    }
+
+    /// Get the count of hardware syncpoints.
+    pub fn num_hw_pts(&self) -> u32 {
+        self.num_hw_pts
+    }
+}
+
 #[repr(packed)]
 pub struct CommandBuffer {
     pub mem: u32,
@@ -100,6 +180,128 @@ pub struct SyncPointIncrement {
     pub syncpoint_incrs: u32,
 }
 
+/// Error reported in [ErrorNotification::info32] when a channel faults.
+///
+/// [ErrorNotification::info32]: struct.ErrorNotification.html#method.info32
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ChannelError {
+    /// The channel didn't make progress before its timeout expired (``NVGPU_ERR_NOTIFIER_FIFO_ERROR_IDLE_TIMEOUT``).
+    FifoIdleTimeout,
+
+    /// The graphics engine raised a software notify interrupt (``NVGPU_ERR_NOTIFIER_GR_ERROR_SW_NOTIFY``).
+    GraphicsSoftwareNotify,
+
+    /// A semaphore acquire of the graphics engine timed out (``NVGPU_ERR_NOTIFIER_GR_SEMAPHORE_TIMEOUT``).
+    GraphicsSemaphoreTimeout,
+
+    /// The graphics engine got an illegal notify (``NVGPU_ERR_NOTIFIER_GR_ILLEGAL_NOTIFY``).
+    GraphicsIllegalNotify,
+
+    /// The channel caused a GPU MMU fault, an access to an unmapped address for example (``NVGPU_ERR_NOTIFIER_FIFO_ERROR_MMU_ERR_FLT``).
+    FifoMmuFault,
+
+    /// The PBDMA failed to fetch or decode the pushbuffer (``NVGPU_ERR_NOTIFIER_PBDMA_ERROR``).
+    PbdmaError,
+
+    /// The channel was reset after a failed verification (``NVGPU_ERR_NOTIFIER_RESETCHANNEL_VERIF_ERROR``).
+    ResetChannelVerificationError,
+
+    /// The CRC of the pushbuffer fetched by the PBDMA didn't match (``NVGPU_ERR_NOTIFIER_PBDMA_PUSHBUFFER_CRC_MISMATCH``).
+    PbdmaPushbufferCrcMismatch,
+
+    /// Any other error code.
+    Unknown(u32),
+}
+
+impl From<u32> for ChannelError {
+    fn from(value: u32) -> Self {
+        match value {
+            8 => ChannelError::FifoIdleTimeout,
+            13 => ChannelError::GraphicsSoftwareNotify,
+            24 => ChannelError::GraphicsSemaphoreTimeout,
+            25 => ChannelError::GraphicsIllegalNotify,
+            31 => ChannelError::FifoMmuFault,
+            32 => ChannelError::PbdmaError,
+            43 => ChannelError::ResetChannelVerificationError,
+            80 => ChannelError::PbdmaPushbufferCrcMismatch,
+            value => ChannelError::Unknown(value),
+        }
+    }
+}
+
+impl From<ChannelError> for u32 {
+    fn from(error: ChannelError) -> u32 {
+        match error {
+            ChannelError::FifoIdleTimeout => 8,
+            ChannelError::GraphicsSoftwareNotify => 13,
+            ChannelError::GraphicsSemaphoreTimeout => 24,
+            ChannelError::GraphicsIllegalNotify => 25,
+            ChannelError::FifoMmuFault => 31,
+            ChannelError::PbdmaError => 32,
+            ChannelError::ResetChannelVerificationError => 43,
+            ChannelError::PbdmaPushbufferCrcMismatch => 80,
+            ChannelError::Unknown(value) => value,
+        }
+    }
+}
+
+/// Represent the error notifier written by the kernel when a channel faults, see [NvHostChannel::set_error_notifier].
+///
+/// [NvHostChannel::set_error_notifier]: struct.NvHostChannel.html#method.set_error_notifier
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ErrorNotification {
+    time_stamp: [u32; 2],
+    info32: u32,
+    info16: u16,
+    status: u16,
+}
+
+impl ErrorNotification {
+    /// Read the error notifier at the given offset of a memory handle.
+    pub fn read(nvmap: &NvMap, handle: &Handle, offset: u32) -> NvHostResult<Self> {
+        let mut data = [0u8; std::mem::size_of::<ErrorNotification>()];
+
+        nvmap.read(handle, offset, &mut data)?;
+
+        Ok(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const ErrorNotification) })
+    }
+
+    /// Check if an error was reported.
+    pub fn is_set(&self) -> bool {
+        self.status != 0
+    }
+
+    /// Get the timestamp (in nanoseconds) of the error.
+    pub fn timestamp(&self) -> u64 {
+        u64::from(self.time_stamp[0]) | (u64::from(self.time_stamp[1]) << 32)
+    }
+
+    /// Get the raw error code.
+    pub fn info32(&self) -> u32 {
+        self.info32
+    }
+
+    /// Get the additional 16 bits information of the error, nvgpu leaves it to 0.
+    pub fn info16(&self) -> u16 {
+        self.info16
+    }
+
+    /// Get the raw status, the kernel sets it to ``0xFFFF`` when it reports an error (0 otherwise).
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Get the error reported.
+    pub fn error(&self) -> Option<ChannelError> {
+        if self.is_set() {
+            Some(ChannelError::from(self.info32))
+        } else {
+            None
+        }
+    }
+}
+
 /// Channel priority used in [NvHost::set_priority]
 ///
 /// [NvHost::set_priority]: struct.NvHost.html#method.set_priority
@@ -107,18 +309,35 @@ pub enum ChannelPriority {
     Low,
     Medium,
     High,
+
+    /// A custom timeslice in microseconds.
+    Custom(u32),
 }
 
-impl From<ChannelPriority> for u32 {
-    fn from(input: ChannelPriority) -> Self {
+/// Convert to the priority level of ``NVHOST_IOCTL_CHANNEL_SET_PRIORITY``.
+///
+/// A custom timeslice only converts if it is the one of a priority level (see [NvHostChannel::set_priority]),
+/// ``EINVAL`` is returned otherwise.
+///
+/// [NvHostChannel::set_priority]: struct.NvHostChannel.html#method.set_priority
+impl TryFrom<ChannelPriority> for u32 {
+    type Error = Errno;
+
+    fn try_from(input: ChannelPriority) -> Result<Self, Self::Error> {
         match input {
-            ChannelPriority::Low => 50,
-            ChannelPriority::Medium => 100,
-            ChannelPriority::High => 150,
+            ChannelPriority::Low | ChannelPriority::Custom(1300) => Ok(50),
+            ChannelPriority::Medium | ChannelPriority::Custom(2600) => Ok(100),
+            ChannelPriority::High | ChannelPriority::Custom(5200) => Ok(150),
+            ChannelPriority::Custom(_) => Err(Errno::EINVAL),
         }
     }
 }
 
+/// Flag of [NvHostChannel::set_timeout_ex] disabling the debug dump on timeout.
+///
+/// [NvHostChannel::set_timeout_ex]: struct.NvHostChannel.html#method.set_timeout_ex
+pub const TIMEOUT_FLAG_DISABLE_DUMP: u32 = 1 << 0;
+
 /// NvHost IOCTLs
 #[allow(dead_code)]
 mod ioctl {
@@ -138,6 +357,9 @@ mod ioctl {
     /// NvHost ioctl magic.
     const NVHOST_IOCTL_MAGIC: u8 = b'H';
 
+    /// Version of the ``NVHOST_IOCTL_CHANNEL_SUBMIT`` interface in use.
+    pub const SUBMIT_VERSION: u32 = 2;
+
     /// Represent the structure of ``NVHOST_IOCTL_CTRL_SYNCPT_INCR``.
     #[repr(C)]
     pub struct SyncPointDoIncrement {
@@ -186,8 +408,8 @@ mod ioctl {
         pub value: u32,
         pub tv_sec: u32,
         pub tv_nsec: u32,
-        reserved_1: u32,
-        reserved_2: u32,
+        pub reserved_1: u32,
+        pub reserved_2: u32,
     }
 
     /// Represent the structure of ``NVHOST_IOCTL_CTRL_MODULE_REGRDWR`` and ``NVHOST_IOCTL_CHANNEL_MODULE_REGRDWR``.
@@ -395,8 +617,8 @@ mod ioctl {
         // ignored by the driver???
         pub waitbases: u64,
         pub class_ids: *const u32,
-        /// fence fds
-        pub fences: *const SyncPointId,
+        /// Out, the threshold reached by each syncpoint increment.
+        pub fences: *mut u32,
     }
 
     /// Represent the structure of ``NVHOST_IOCTL_CHANNEL_SET_SYNCPOINT_NAME``.
@@ -404,7 +626,7 @@ mod ioctl {
     pub struct SetSyncPointNameArguments {
         pub name: *const u8,
         pub syncpoint_id: SyncPointId,
-        padding: u32,
+        pub padding: u32,
     }
 
     /// Represnet the structure of ``NVHOST_IOCTL_CHANNEL_SET_ERROR_NOTIFIER``.
@@ -413,7 +635,7 @@ mod ioctl {
         pub offset: u64,
         pub size: u64,
         pub mem: u32,
-        padding: u32,
+        pub padding: u32,
     }
 
     /// Represent the structure of ``NVHOST_IOCTL_CHANNEL_OPEN``.
@@ -540,6 +762,34 @@ mod ioctl {
 
 use ioctl::*;
 
+/// Call the given ioctl again as long as it is interrupted by a signal.
+fn ioctl_retry<F: FnMut() -> nix::Result<i32>>(mut ioctl: F) -> nix::Result<i32> {
+    loop {
+        match ioctl() {
+            Err(Errno::EINTR) => continue,
+            res => return res,
+        }
+    }
+}
+
+/// Convert the result of an ioctl call, keeping the errno reported by the kernel.
+fn map_ioctl_result(res: nix::Result<i32>) -> NvHostResult<()> {
+    match res {
+        Err(errno) => Err(errno),
+        Ok(0) => Ok(()),
+        Ok(errno) => Err(Errno::from_i32(errno)),
+    }
+}
+
+/// Convert the result of a syncpoint wait ioctl, a timeout is reported as ``ETIMEDOUT``.
+fn map_wait_result(res: nix::Result<i32>) -> NvHostResult<()> {
+    match map_ioctl_result(res) {
+        // The kernel reports an expired timeout as EAGAIN.
+        Err(Errno::EAGAIN) | Err(Errno::ETIMEDOUT) => Err(Errno::ETIMEDOUT),
+        res => res,
+    }
+}
+
 impl NvHostCtrl {
     /// Create a new instance of NvHostCtrl by opening `/dev/nvhost-ctrl`.
     pub fn new() -> std::io::Result<Self> {
@@ -561,6 +811,288 @@ impl NvHostCtrl {
     pub fn as_raw_fd(&self) -> RawFd {
         self.file.as_raw_fd()
     }
+
+    /// Read the current value of a given syncpoint.
+    pub fn read_syncpoint(&self, id: SyncPointId) -> NvHostResult<u32> {
+        let mut param = RawFence { id, value: 0 };
+
+        let res = unsafe { ioc_ctrl_syncpoint_read(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Ok(param.value)
+    }
+
+    /// Read the maximum value of a given syncpoint.
+    ///
+    /// NOTE: this is the value the syncpoint will reach once all pending work is complete.
+    pub fn read_syncpoint_max(&self, id: SyncPointId) -> NvHostResult<u32> {
+        let mut param = RawFence { id, value: 0 };
+
+        let res = unsafe { ioc_ctrl_syncpoint_read_max(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Ok(param.value)
+    }
+
+    /// Get the version of the nvhost interface.
+    pub fn get_version(&self) -> NvHostResult<u32> {
+        let mut param = GetParamArguments { value: 0 };
+
+        let res = unsafe { ioc_ctrl_get_version(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Ok(param.value)
+    }
+
+    /// Get the characteristics of nvhost.
+    pub fn get_characteristics(&self) -> NvHostResult<Characteristics> {
+        let mut characteristics = Characteristics::default();
+
+        let mut param = GetCharacteristics {
+            characteristics_size: std::mem::size_of::<Characteristics>() as u64,
+            characteristics_address: &mut characteristics as *mut Characteristics,
+        };
+
+        let res = unsafe { ioc_ctrl_get_characteristics(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Ok(characteristics)
+    }
+
+    /// Create a sync_file fence signaled once all the given syncpoints reach their threshold.
+    pub fn create_fence(
+        &self,
+        name: &str,
+        points: &[(SyncPointId, u32)],
+    ) -> NvHostResult<SyncFence> {
+        let name = CString::new(name).map_err(|_| Errno::EINVAL)?;
+
+        // NOTE: must stay alive until the ioctl returns.
+        let points: Vec<SyncFenceInfo> = points
+            .iter()
+            .map(|&(id, threshhold)| SyncFenceInfo { id, threshhold })
+            .collect();
+
+        let mut param = SyncFenceCreate {
+            num_pts: points.len() as u32,
+            fence_fd: -1,
+            pts: points.as_ptr(),
+            name: name.as_ptr() as *const u8,
+        };
+
+        let res = unsafe { ioc_ctrl_sync_fence_create(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Ok(SyncFence { fd: param.fence_fd })
+    }
+
+    /// Lock or unlock the given hardware module mutex.
+    fn module_mutex(&self, id: SyncPointId, lock: bool) -> NvHostResult<()> {
+        let mut param = ModuleMutex {
+            id,
+            lock: lock as u32,
+        };
+
+        let res =
+            ioctl_retry(|| unsafe { ioc_ctrl_module_mutex(self.file.as_raw_fd(), &mut param) });
+        map_ioctl_result(res)
+    }
+
+    /// Lock the given hardware module mutex, it is unlocked when the returned guard is dropped.
+    pub fn lock_module_mutex(&self, id: SyncPointId) -> NvHostResult<ModuleMutexGuard<'_>> {
+        self.module_mutex(id, true)?;
+
+        Ok(ModuleMutexGuard { ctrl: self, id })
+    }
+
+    /// Read or write registers of a given module, one 32 bits register per offset.
+    fn module_register_readwrite(
+        &self,
+        id: SyncPointId,
+        offsets: &[u32],
+        values: &mut [u32],
+        write: bool,
+    ) -> NvHostResult<()> {
+        if offsets.len() > u32::MAX as usize || offsets.len() != values.len() {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut param = ModuleRegisterReadWrite {
+            id,
+            num_offsets: offsets.len() as u32,
+            block_size: std::mem::size_of::<u32>() as u32,
+            write: write as u32,
+            offsets: offsets.as_ptr() as u64,
+            values: values.as_mut_ptr() as u64,
+        };
+
+        let res = unsafe { ioc_ctrl_module_register_readwrite(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)
+    }
+
+    /// Read the registers at the given offsets of a given module.
+    pub fn module_register_read(&self, id: SyncPointId, offsets: &[u32]) -> NvHostResult<Vec<u32>> {
+        let mut values = vec![0; offsets.len()];
+
+        self.module_register_readwrite(id, offsets, &mut values, false)?;
+
+        Ok(values)
+    }
+
+    /// Write the given (offset, value) pairs to the registers of a given module.
+    pub fn module_register_write(
+        &self,
+        id: SyncPointId,
+        writes: &[(u32, u32)],
+    ) -> NvHostResult<()> {
+        let offsets: Vec<u32> = writes.iter().map(|&(offset, _)| offset).collect();
+        let mut values: Vec<u32> = writes.iter().map(|&(_, value)| value).collect();
+
+        self.module_register_readwrite(id, &offsets, &mut values, true)
+    }
+
+    /// Check if the given module (VIC, NVENC, ...) is available on this SoC.
+    pub fn is_module_supported(&self, module_id: u32) -> NvHostResult<bool> {
+        let mut param = CheckModuleSupport {
+            module_id,
+            value: 0,
+        };
+
+        let res = unsafe { ioc_ctrl_check_module_support(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Ok(param.value != 0)
+    }
+
+    /// Increment a given syncpoint from the CPU.
+    pub fn increment_syncpoint(&self, id: SyncPointId) -> NvHostResult<()> {
+        let param = SyncPointDoIncrement { id };
+
+        let res = unsafe { ioc_ctrl_syncpoint_increment(self.file.as_raw_fd(), &param) };
+        map_ioctl_result(res)
+    }
+
+    /// Wait for a given syncpoint to reach the given threshold.
+    ///
+    /// Returns ``ETIMEDOUT`` if the threshold wasn't reached before the timeout expired.
+    ///
+    /// NOTE: a negative timeout means an infinite wait.
+    pub fn wait_syncpoint(
+        &self,
+        id: SyncPointId,
+        threshold: u32,
+        timeout_ms: i32,
+    ) -> NvHostResult<()> {
+        let param = SyncPointWait {
+            id,
+            threshhold: threshold,
+            timeout: timeout_ms,
+        };
+
+        let res = ioctl_retry(|| unsafe { ioc_ctrl_syncpoint_wait(self.file.as_raw_fd(), &param) });
+        map_wait_result(res)
+    }
+
+    /// Wait for a given syncpoint to reach the given threshold, returning the value of the syncpoint
+    /// and the time (``CLOCK_MONOTONIC``) at which the threshold was reached.
+    ///
+    /// Returns ``ETIMEDOUT`` if the threshold wasn't reached before the timeout expired.
+    ///
+    /// NOTE: a negative timeout means an infinite wait.
+    pub fn wait_syncpoint_timestamped(
+        &self,
+        id: SyncPointId,
+        threshold: u32,
+        timeout_ms: i32,
+    ) -> NvHostResult<(u32, Duration)> {
+        let mut param = SyncPointWaitMEx {
+            id,
+            threshhold: threshold,
+            timeout: timeout_ms,
+            value: 0,
+            tv_sec: 0,
+            tv_nsec: 0,
+            reserved_1: 0,
+            reserved_2: 0,
+        };
+
+        let res = ioctl_retry(|| unsafe {
+            ioc_ctrl_syncpoint_waitmex(self.file.as_raw_fd(), &mut param)
+        });
+        map_wait_result(res)?;
+
+        Ok((
+            param.value,
+            Duration::new(u64::from(param.tv_sec), param.tv_nsec),
+        ))
+    }
+}
+
+impl AsRawFd for NvHostCtrl {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for NvHostCtrl {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+impl<'a> ModuleMutexGuard<'a> {
+    /// Get the identifier of the locked module mutex.
+    pub fn id(&self) -> SyncPointId {
+        self.id
+    }
+}
+
+impl<'a> Drop for ModuleMutexGuard<'a> {
+    fn drop(&mut self) {
+        let _ = self.ctrl.module_mutex(self.id, false);
+    }
+}
+
+impl SyncFence {
+    /// Create a new instance of SyncFence from a file descriptor, the file descriptor is now owned by the fence.
+    pub fn new_from_raw_fd(fd: RawFd) -> Self {
+        SyncFence { fd }
+    }
+
+    /// Set the name of this fence.
+    pub fn set_name(&self, ctrl: &NvHostCtrl, name: &str) -> NvHostResult<()> {
+        let name = CString::new(name).map_err(|_| Errno::EINVAL)?;
+
+        let mut param = SyncFenceSetName {
+            name: name.as_ptr() as *const u8,
+            fence_fd: self.fd,
+        };
+
+        let res = unsafe { ioc_ctrl_sync_fence_set_name(ctrl.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)
+    }
+}
+
+impl Drop for SyncFence {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.fd);
+    }
+}
+
+impl AsRawFd for SyncFence {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl IntoRawFd for SyncFence {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+
+        std::mem::forget(self);
+
+        fd
+    }
 }
 
 impl NvHostChannel {
@@ -587,6 +1119,18 @@ impl NvHostChannel {
         Ok(res)
     }
 
+    /// Duplicate the file descriptor of this channel.
+    ///
+    /// The duplicate refers to the same kernel channel, the nvmap association is shared.
+    pub fn try_clone(&self) -> NvHostResult<Self> {
+        let file = self
+            .file
+            .try_clone()
+            .map_err(|error| Errno::from_i32(error.raw_os_error().unwrap_or(0)))?;
+
+        Ok(NvHostChannel { file })
+    }
+
     /// Assign the given nvmap file descriptor to this channel.
     pub fn set_nvmap_fd(&self, fd: RawFd) -> NvHostResult<()> {
         let param = SetNvMapFdArguments { fd };
@@ -609,6 +1153,8 @@ impl NvHostChannel {
             ChannelPriority::High => 5200,
             ChannelPriority::Medium => 2600,
             ChannelPriority::Low => 1300,
+            ChannelPriority::Custom(0) => return Err(Errno::EINVAL),
+            ChannelPriority::Custom(timeslice_us) => timeslice_us,
         };
 
         self.set_timeslice(timeslice_us)
@@ -637,10 +1183,259 @@ impl NvHostChannel {
         }
     }
 
-    ///pub fn set_error_notifier(&self, )
+    /// Set the timeout of submissions on this channel.
+    pub fn set_timeout(&self, timeout_ms: u32) -> NvHostResult<()> {
+        let param = SetTimeoutArguments {
+            timeout: timeout_ms,
+        };
+
+        let res = unsafe { ioc_channel_set_timeout(self.file.as_raw_fd(), &param) };
+        match res {
+            // Not implemented by all kernels.
+            Err(Errno::ENOTTY) => Ok(()),
+            res => map_ioctl_result(res),
+        }
+    }
+
+    /// Set the timeout of submissions on this channel with the given flags (see [TIMEOUT_FLAG_DISABLE_DUMP]).
+    ///
+    /// [TIMEOUT_FLAG_DISABLE_DUMP]: constant.TIMEOUT_FLAG_DISABLE_DUMP.html
+    pub fn set_timeout_ex(&self, timeout_ms: u32, flags: u32) -> NvHostResult<()> {
+        let mut param = SetTimeoutExArguments {
+            timeout: timeout_ms,
+            flags,
+        };
+
+        let res = unsafe { ioc_channel_set_timeout_ex(self.file.as_raw_fd(), &mut param) };
+        match res {
+            // Not implemented by all kernels.
+            Err(Errno::ENOTTY) => Ok(()),
+            res => map_ioctl_result(res),
+        }
+    }
+
+    /// Get the clock rate (in Hz) of the engine behind this channel.
+    ///
+    /// The lower 24 bits of ``module_id`` select the clock of the engine (0 being the main clock, the GPU clock on a GPU channel),
+    /// the upper 8 bits select the attribute (0 for the rate, 1 for the bandwidth).
+    ///
+    /// NOTE: 0 is returned if the kernel doesn't support clock control.
+    pub fn get_clock_rate(&self, module_id: u32) -> NvHostResult<u32> {
+        let mut param = ClockRateArguments { rate: 0, module_id };
+
+        let res = unsafe { ioc_channel_get_clock_rate(self.file.as_raw_fd(), &mut param) };
+        match res {
+            // Not all kernels allow userland clock control.
+            Err(Errno::ENOTTY) => Ok(0),
+            res => map_ioctl_result(res).map(|_| param.rate),
+        }
+    }
+
+    /// Set the clock rate (in Hz) of the engine behind this channel.
+    ///
+    /// See [NvHostChannel::get_clock_rate] for the possible values of ``module_id``.
+    ///
+    /// [NvHostChannel::get_clock_rate]: struct.NvHostChannel.html#method.get_clock_rate
+    pub fn set_clock_rate(&self, rate: u32, module_id: u32) -> NvHostResult<()> {
+        let param = ClockRateArguments { rate, module_id };
+
+        let res = unsafe { ioc_channel_set_clock_rate(self.file.as_raw_fd(), &param) };
+        match res {
+            // Not all kernels allow userland clock control.
+            Err(Errno::ENOTTY) => Ok(()),
+            res => map_ioctl_result(res),
+        }
+    }
+
+    /// Submit command buffers to the engine behind this channel using the legacy nvhost interface.
+    ///
+    /// ``shifts`` must have one entry per relocation, ``incrs`` must not be empty.
+    /// The returned fence is the one of the first syncpoint increment.
+    pub fn submit(
+        &self,
+        cmdbufs: &[CommandBuffer],
+        relocs: &[Relocation],
+        shifts: &[RelocationShift],
+        waitchks: &[WaitChk],
+        incrs: &[SyncPointIncrement],
+        timeout: u32,
+    ) -> NvHostResult<RawFence> {
+        if incrs.is_empty() || relocs.len() != shifts.len() {
+            return Err(Errno::EINVAL);
+        }
+
+        // NOTE: all those arrays must stay alive until the ioctl returns.
+        let cmdbuf_exts: Vec<CommandBufferExt> = cmdbufs
+            .iter()
+            .map(|_| CommandBufferExt {
+                pre_fence: -1,
+                reserved: 0,
+            })
+            .collect();
+        let reloc_types: Vec<RelocationType> = relocs
+            .iter()
+            .map(|_| RelocationType {
+                reloc_type: 0,
+                padding: 0,
+            })
+            .collect();
+        let mut fences: Vec<u32> = vec![0; incrs.len()];
+
+        let mut param = SubmitArguments {
+            submit_version: SUBMIT_VERSION,
+            num_syncpt_incrs: incrs.len() as u32,
+            num_cmdbufs: cmdbufs.len() as u32,
+            num_relocs: relocs.len() as u32,
+            num_waitchks: waitchks.len() as u32,
+            timeout,
+            flags: 0,
+            fence: 0,
+            syncpt_incrs: incrs.as_ptr(),
+            cmdbuf_exts: cmdbuf_exts.as_ptr(),
+            checksum_methods: 0,
+            checksum_falcon_methods: 0,
+            reserved_for_future_use: 0,
+            reloc_types: reloc_types.as_ptr(),
+            cmdbufs: cmdbufs.as_ptr(),
+            relocs: relocs.as_ptr(),
+            reloc_shifts: shifts.as_ptr(),
+            waitchks: waitchks.as_ptr(),
+            waitbases: 0,
+            class_ids: std::ptr::null(),
+            fences: fences.as_mut_ptr(),
+        };
+
+        let res = unsafe { ioc_channel_submit(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        // NOTE: ``fence`` is the threshold of the channel syncpoint, which isn't always the first increment.
+        Ok(RawFence {
+            id: incrs[0].syncpoint_id,
+            value: fences[0],
+        })
+    }
+
+    /// Allocate a syncpoint managed by the client, owned by this channel.
+    pub fn allocate_client_managed_syncpoint(&self, name: &str) -> NvHostResult<SyncPointId> {
+        let name = CString::new(name).map_err(|_| Errno::EINVAL)?;
+
+        let mut param = GetClientManagedSyncPointArgument {
+            name: name.as_ptr() as *const u8,
+            param: 0,
+            value: 0,
+        };
+
+        let res =
+            unsafe { ioc_channel_get_client_managed_syncpoint(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Ok(param.value as SyncPointId)
+    }
+
+    /// Set the name of a syncpoint of this channel (as shown in the nvhost debugfs).
+    pub fn set_syncpoint_name(&self, id: SyncPointId, name: &str) -> NvHostResult<()> {
+        let name = CString::new(name).map_err(|_| Errno::EINVAL)?;
+
+        let param = SetSyncPointNameArguments {
+            name: name.as_ptr() as *const u8,
+            syncpoint_id: id,
+            padding: 0,
+        };
+
+        let res = unsafe { ioc_channel_set_syncpoint_name(self.file.as_raw_fd(), &param) };
+        map_ioctl_result(res)
+    }
+
+    /// Get the syncpoint assigned to this channel at the given index.
+    pub fn get_syncpoint(&self, index: u32) -> NvHostResult<SyncPointId> {
+        let mut param = GetParamValueArgument {
+            param: index,
+            value: 0,
+        };
+
+        let res = unsafe { ioc_channel_get_syncpoint(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Ok(param.value as SyncPointId)
+    }
+
+    /// Make the kernel report channel errors to the given range of a memory handle.
+    ///
+    /// The range must be able to hold an [ErrorNotification] that can be read back with [ErrorNotification::read].
+    /// The memory is given to the kernel by its dmabuf file descriptor, the handle must have one.
+    ///
+    /// [ErrorNotification]: struct.ErrorNotification.html
+    /// [ErrorNotification::read]: struct.ErrorNotification.html#method.read
+    pub fn set_error_notifier(&self, handle: &Handle, offset: u64, size: u64) -> NvHostResult<()> {
+        if size < std::mem::size_of::<ErrorNotification>() as u64 || handle.fd < 0 {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut param = SetErrorNotifier {
+            offset,
+            size,
+            mem: handle.fd as u32,
+            padding: 0,
+        };
+
+        let res = unsafe { ioc_channel_set_error_notifier(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)
+    }
 
     /// Get the file descriptor used.
     pub fn as_raw_fd(&self) -> RawFd {
         self.file.as_raw_fd()
     }
 }
+
+impl AsRawFd for NvHostChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for NvHostChannel {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_priority_levels() {
+        assert_eq!(u32::try_from(ChannelPriority::Low), Ok(50));
+        assert_eq!(u32::try_from(ChannelPriority::Medium), Ok(100));
+        assert_eq!(u32::try_from(ChannelPriority::High), Ok(150));
+        assert_eq!(u32::try_from(ChannelPriority::Custom(5200)), Ok(150));
+
+        assert_eq!(
+            u32::try_from(ChannelPriority::Custom(0)),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(
+            u32::try_from(ChannelPriority::Custom(4000)),
+            Err(Errno::EINVAL)
+        );
+    }
+
+    #[test]
+    #[ignore = "requires an nvhost device"]
+    fn dropping_module_mutex_guard_unlocks() {
+        let ctrl = NvHostCtrl::new().unwrap();
+        let other_ctrl = NvHostCtrl::new().unwrap();
+
+        // Mutex 0 is the host module refcount, only the others are exclusive.
+        assert!(ctrl.get_characteristics().unwrap().num_mlocks() > 1);
+
+        let guard = ctrl.lock_module_mutex(1).unwrap();
+        assert_eq!(guard.id(), 1);
+        assert_eq!(other_ctrl.lock_module_mutex(1).err(), Some(Errno::EBUSY));
+
+        drop(guard);
+
+        other_ctrl.lock_module_mutex(1).unwrap();
+    }
+}
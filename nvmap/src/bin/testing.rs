@@ -6,20 +6,17 @@ use nvmap::*;
 fn main() -> NvMapResult<()> {
     let nvmap = NvMap::new().unwrap();
 
-    let mut handle = nvmap.create(0x1000)?;
-    println!("New handle: {:x}", handle.raw_handle);
-
-    nvmap.allocate(
-        &handle,
+    let mut handle = nvmap.alloc_mapped(
+        0x1000,
         HeapMask::CARVEOUT_GENERIC,
         AllocationFlags::HANDLE_WRITE_COMBINE,
         0x10,
     )?;
+    println!("New handle: {:x}", handle.raw_handle);
 
     let fd = nvmap.get_fd(handle.raw_handle)?;
     let mut handle_duplicate = nvmap.create_from_fd(fd, handle.size())?;
 
-    nvmap.map(&mut handle)?;
     nvmap.map(&mut handle_duplicate)?;
 
     unsafe {
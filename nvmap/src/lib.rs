@@ -6,14 +6,20 @@ use bitflags::bitflags;
 
 use nix::errno::Errno;
 
+use core::convert::TryFrom;
+
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::FromRawFd;
+use std::os::unix::io::IntoRawFd;
 use std::os::unix::io::RawFd;
+use std::sync::OnceLock;
 
 use nix::sys::mman::*;
 
+pub use nix::sys::mman::ProtFlags;
+
 /// This is the raw representation of a NvMap handle.
 ///
 /// NOTE: this is the handle returned by the driver.
@@ -23,7 +29,7 @@ pub type RawHandle = u32;
 #[derive(Debug)]
 pub struct Handle {
     /// The size of the memory region behind the memory handle.
-    pub size: u32,
+    pub size: u64,
 
     /// The memory handle.
     pub raw_handle: RawHandle,
@@ -33,8 +39,31 @@ pub struct Handle {
 
     /// The mapped address of the memory handle.
     mapped_address: Option<*mut u8>,
+
+    /// The offset in the memory handle of the start of the mapping.
+    mapped_offset: u64,
+
+    /// The length of the mapping.
+    mapped_length: usize,
+
+    /// The protection of the mapping.
+    mapped_prot: ProtFlags,
+
+    /// A duplicate of the file descriptor of the NvMap instance used to free the handle on drop.
+    owner: Option<File>,
+
+    /// Whether the file descriptor was created by NvMap and must be closed on drop.
+    owns_fd: bool,
+
+    /// Set when the handle was already freed with [NvMap::free].
+    ///
+    /// [NvMap::free]: struct.NvMap.html#method.free
+    freed: bool,
 }
 
+/// The page size used by nvmap.
+pub const PAGE_SIZE: usize = 0x1000;
+
 /// The result of NvMap operations.
 pub type NvMapResult<T> = std::result::Result<T, Errno>;
 
@@ -46,7 +75,7 @@ pub struct NvMap {
 
 impl Handle {
     /// Get the size of the memory region behind the memory handle.
-    pub fn size(&self) -> u32 {
+    pub fn size(&self) -> u64 {
         self.size
     }
 
@@ -59,17 +88,160 @@ impl Handle {
         self.mapped_address
     }
 
+    /// Write back and invalidate the CPU cache for a range of the mapping.
+    ///
+    /// Returns ``EINVAL`` if the range is out of the bounds of the handle.
+    pub fn flush_range(&self, nvmap: &NvMap, offset: u32, size: u32) -> NvMapResult<()> {
+        checked_range_size(self, offset, size as usize)?;
+        nvmap.writeback_invalidate(self, offset, size)
+    }
+
+    /// Invalidate the CPU cache for a range of the mapping.
+    ///
+    /// Returns ``EINVAL`` if the range is out of the bounds of the handle.
+    pub fn invalidate_range(&self, nvmap: &NvMap, offset: u32, size: u32) -> NvMapResult<()> {
+        checked_range_size(self, offset, size as usize)?;
+        nvmap.invalidate(self, offset, size)
+    }
+
+    /// Get the offset in the memory handle of the start of the mapping.
+    pub fn mapped_offset(&self) -> u64 {
+        self.mapped_offset
+    }
+
+    /// Get the length of the mapping (0 if not mapped).
+    pub fn mapped_length(&self) -> usize {
+        self.mapped_length
+    }
+
+    /// Check if the given window is mapped with the given protection.
+    ///
+    /// Returns ``EEXIST`` if another window or protection is mapped, an existing mapping is never silently changed.
+    fn is_mapped_as(&self, offset: u64, length: usize, prot: ProtFlags) -> NvMapResult<bool> {
+        if self.mapped_address.is_none() {
+            return Ok(false);
+        }
+
+        if self.mapped_offset != offset || self.mapped_length != length || self.mapped_prot != prot
+        {
+            return Err(Errno::EEXIST);
+        }
+
+        Ok(true)
+    }
+
+    /// Get the mapped address of the given range of the memory handle if it is fully mapped.
+    fn mapped_range(&self, offset: u32, size: u32) -> Option<*mut u8> {
+        let mapped_address = self.mapped_address?;
+        let start = u64::from(offset).checked_sub(self.mapped_offset)?;
+
+        if start + u64::from(size) > self.mapped_length as u64 {
+            return None;
+        }
+
+        Some(unsafe { mapped_address.add(start as usize) })
+    }
+
     /// Creater a new Handle instance.
     ///
     /// NOTE: to allocate a new Handle please use [NvMap::create]
     ///
     /// [NvMap::create]: struct.NvMap.html#method.create
-    pub fn from_raw(raw_handle: RawHandle, fd: RawFd, size: u32) -> Self {
+    ///
+    /// NOTE: Handles created this way are not freed on drop.
+    pub fn from_raw(raw_handle: RawHandle, fd: RawFd, size: u64) -> Self {
         Handle {
             size,
             raw_handle,
             fd,
             mapped_address: None,
+            mapped_offset: 0,
+            mapped_length: 0,
+            mapped_prot: ProtFlags::PROT_NONE,
+            owner: None,
+            owns_fd: false,
+            freed: false,
+        }
+    }
+
+    /// Create a Handle freed by the given NvMap instance on drop.
+    ///
+    /// NOTE: The file descriptor of the NvMap instance is duplicated, the handle can outlive it.
+    /// On error, the memory handle is freed and the file descriptor closed if owned.
+    #[allow(clippy::cast_possible_wrap)]
+    fn new_owned(
+        nvmap: &NvMap,
+        raw_handle: RawHandle,
+        fd: RawFd,
+        size: u64,
+        owns_fd: bool,
+    ) -> NvMapResult<Self> {
+        let owner = match nix::unistd::dup(nvmap.as_raw_fd()) {
+            Ok(owner) => unsafe { File::from_raw_fd(owner) },
+            Err(error) => {
+                let _ = unsafe { ioc_free(nvmap.as_raw_fd(), raw_handle as i32) };
+
+                if owns_fd {
+                    let _ = nix::unistd::close(fd);
+                }
+
+                return Err(error);
+            }
+        };
+
+        let mut res = Self::from_raw(raw_handle, fd, size);
+
+        res.owner = Some(owner);
+        res.owns_fd = owns_fd;
+
+        Ok(res)
+    }
+
+    /// Give up the ownership of the memory handle without freeing it, the file descriptor is kept open.
+    ///
+    /// This is useful when the file descriptor is shared with another process.
+    pub fn leak(mut self) -> (RawHandle, RawFd) {
+        // The CPU mapping would not be reachable anymore.
+        if let Some(addr) = self.mapped_address.take() {
+            let _ = unsafe { munmap(addr as *mut _, self.mapped_length) };
+        }
+
+        let res = (self.raw_handle, self.fd);
+
+        drop(self.owner.take());
+        std::mem::forget(self);
+
+        res
+    }
+}
+
+impl AsRawFd for Handle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+// SAFETY: The mapping is shared by the whole process, the raw pointer is only an address and is never dereferenced by Handle itself.
+// Accessing the mapped memory is unsafe and left to the caller.
+unsafe impl Send for Handle {}
+
+// SAFETY: See above, no method taking &self accesses the mapped memory.
+unsafe impl Sync for Handle {}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        if let Some(addr) = self.mapped_address.take() {
+            let _ = unsafe { munmap(addr as *mut _, self.mapped_length) };
+        }
+
+        if let Some(owner) = self.owner.take() {
+            if !self.freed {
+                let _ = unsafe { ioc_free(owner.as_raw_fd(), self.raw_handle as i32) };
+            }
+
+            if self.owns_fd {
+                let _ = nix::unistd::close(self.fd);
+            }
         }
     }
 }
@@ -104,6 +276,31 @@ bitflags! {
     }
 }
 
+impl HeapMask {
+    /// Get every heap in the order they should be tried (IOVMM first, then carveouts).
+    pub fn fallback_order() -> [HeapMask; 7] {
+        [
+            HeapMask::IOVMM,
+            HeapMask::CARVEOUT_IRAM,
+            HeapMask::CARVEOUT_VPR,
+            HeapMask::CARVEOUT_TSEC,
+            HeapMask::CARVEOUT_VIDMEM,
+            HeapMask::CARVEOUT_IVM,
+            HeapMask::CARVEOUT_GENERIC,
+        ]
+    }
+
+    /// Iterate over each heap set in this mask following [HeapMask::fallback_order].
+    ///
+    /// [HeapMask::fallback_order]: struct.HeapMask.html#method.fallback_order
+    pub fn iter(self) -> impl Iterator<Item = HeapMask> {
+        Self::fallback_order()
+            .to_vec()
+            .into_iter()
+            .filter(move |heap| self.contains(*heap))
+    }
+}
+
 bitflags! {
     /// Allocation flags used in [NvMap::allocate]
     ///
@@ -123,14 +320,95 @@ bitflags! {
     }
 }
 
-/// Flush operation flag for ``NVMAP_IOC_CACHE``.
-const CACHE_OPERATION_WRITE_BACK: i32 = 0;
+impl AllocationFlags {
+    /// Preset for buffers written by the CPU and read by the GPU.
+    pub fn upload() -> Self {
+        AllocationFlags::HANDLE_WRITE_COMBINE
+    }
 
-/// Invalidate operation flag for ``NVMAP_IOC_CACHE``.
-const CACHE_OPERATION_INVALIDATE: i32 = 1;
+    /// Preset for buffers written by the GPU and read back by the CPU.
+    pub fn readback() -> Self {
+        AllocationFlags::HANDLE_CACHEABLE
+    }
 
-/// Flush & Invalidate operation flag for ``NVMAP_IOC_CACHE``.
-const CACHE_OPERATION_WRITE_BACK_INVALIDATE: i32 = 2;
+    /// Preset for buffers that must never be cached by the CPU.
+    pub fn uncached() -> Self {
+        AllocationFlags::HANDLE_UNCACHEABLE
+    }
+
+    /// Pack the given allocation tag in the upper bits of the flags.
+    pub fn with_tag(self, tag: u16) -> Self {
+        // SAFETY: the tag bits are not defined as flags on purpose, they are passed as is to the kernel.
+        unsafe { Self::from_bits_unchecked((self.bits() & 0xFFFF) | (u32::from(tag) << 16)) }
+    }
+
+    /// Get the allocation tag packed in the flags if any.
+    pub fn tag(&self) -> Option<u16> {
+        match (self.bits() >> 16) as u16 {
+            0 => None,
+            tag => Some(tag),
+        }
+    }
+}
+
+/// Check that a range of the given length at the given offset fits in the memory handle and return its length.
+fn checked_range_size(handle: &Handle, offset: u32, length: usize) -> NvMapResult<u32> {
+    let size = u32::try_from(length).map_err(|_| Errno::EINVAL)?;
+
+    match offset.checked_add(size) {
+        Some(end) if u64::from(end) <= handle.size() => Ok(size),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Convert the result of an ioctl call, keeping the errno reported by the kernel.
+fn map_ioctl_result(res: nix::Result<i32>) -> NvMapResult<()> {
+    match res {
+        Err(errno) => Err(errno),
+        Ok(0) => Ok(()),
+        Ok(errno) => Err(Errno::from_i32(errno)),
+    }
+}
+
+/// Compute a non zero allocation tag from a name (FNV-1a folded to 16 bits).
+fn tag_from_name(name: &str) -> u16 {
+    let mut hash: u32 = 0x811C_9DC5;
+
+    for byte in name.bytes() {
+        hash ^= u32::from(byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+
+    match ((hash >> 16) ^ (hash & 0xFFFF)) as u16 {
+        0 => NvMap::DEFAULT_TAG as u16,
+        tag => tag,
+    }
+}
+
+/// Cache maintenance operation used in [NvMap::cache_maintenance]
+///
+/// [NvMap::cache_maintenance]: struct.NvMap.html#method.cache_maintenance
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CacheOp {
+    /// Flush the CPU cache.
+    WriteBack,
+
+    /// Invalidate the CPU cache.
+    Invalidate,
+
+    /// Flush & Invalidate the CPU cache.
+    WriteBackInvalidate,
+}
+
+impl From<CacheOp> for i32 {
+    fn from(operation: CacheOp) -> i32 {
+        match operation {
+            CacheOp::WriteBack => 0,
+            CacheOp::Invalidate => 1,
+            CacheOp::WriteBackInvalidate => 2,
+        }
+    }
+}
 
 /// Internal module managing raw ioctls calls.
 mod ioctl {
@@ -159,6 +437,16 @@ mod ioctl {
         pub handle: RawHandle,
     }
 
+    /// Structure for ``NVMAP_IOC_GET_ID`` and ``NVMAP_IOC_FROM_ID``.
+    #[repr(C)]
+    pub struct HandleId {
+        /// The global id of the memory handle. (Output for GET_ID, Input for FROM_ID)
+        pub id: u32,
+
+        /// The memory handle. (Input for GET_ID, Output for FROM_ID)
+        pub handle: RawHandle,
+    }
+
     /// Structure for ``NVMAP_IOC_FROM_FD``.
     #[repr(C)]
     pub struct CreateHandleFromFd {
@@ -185,6 +473,22 @@ mod ioctl {
         pub operation: i32,
     }
 
+    /// Structure for ``NVMAP_IOC_FROM_VA``.
+    #[repr(C)]
+    pub struct CreateHandleFromVa {
+        /// The user virtual address backing the memory handle. (Input)
+        pub va: u64,
+
+        /// The size of the region to use. (Input)
+        pub size: u32,
+
+        /// The flags of the memory region. (Input)
+        pub flags: u32,
+
+        /// The resulting memory handle. (Output)
+        pub handle: RawHandle,
+    }
+
     /// Structure for ``NVMAP_IOC_ALLOC``.
     #[repr(C)]
     pub struct AllocateHandle {
@@ -201,11 +505,37 @@ mod ioctl {
         pub align: u32,
     }
 
+    /// Structure for ``NVMAP_IOC_ALLOC_KIND``.
+    #[repr(C)]
+    pub struct AllocateKindHandle {
+        /// The memory handle that needs memory. (Input)
+        pub handle: RawHandle,
+
+        /// The heap to allocate from. (Input)
+        pub heap_mask: u32,
+
+        /// The flags of the memory region. (Input)
+        pub flags: u32,
+
+        /// The alignment needed. (Input)
+        pub align: u32,
+
+        /// The GPU memory kind of the memory region. (Input)
+        pub kind: u8,
+
+        /// The count of compression tags to reserve. (Input)
+        pub comp_tags: u8,
+    }
+
     ioctl_readwrite!(ioc_create, NVMAP_IOC_MAGIC, 0, CreateHandle);
+    ioctl_readwrite!(ioc_from_id, NVMAP_IOC_MAGIC, 2, HandleId);
     ioctl_write_ptr!(ioc_allocate, NVMAP_IOC_MAGIC, 3, AllocateHandle);
     ioctl_write_ptr!(ioc_cache, NVMAP_IOC_MAGIC, 12, HandleCacheMaintenance);
+    ioctl_readwrite!(ioc_get_id, NVMAP_IOC_MAGIC, 13, HandleId);
     ioctl_readwrite!(ioc_get_fd, NVMAP_IOC_MAGIC, 15, HandleGetFd);
     ioctl_readwrite!(ioc_from_fd, NVMAP_IOC_MAGIC, 16, CreateHandleFromFd);
+    ioctl_write_ptr!(ioc_allocate_kind, NVMAP_IOC_MAGIC, 21, AllocateKindHandle);
+    ioctl_readwrite!(ioc_from_va, NVMAP_IOC_MAGIC, 22, CreateHandleFromVa);
     ioctl_write_int_bad!(ioc_free, request_code_none!(NVMAP_IOC_MAGIC, 4));
 }
 
@@ -217,6 +547,22 @@ impl NvMap {
     /// Guaranteed to be random.
     pub const DEFAULT_TAG: u32 = 0xCAFE;
 
+    /// Get the allocation tag used when none is given, derived from the process name.
+    ///
+    /// Falls back to [NvMap::DEFAULT_TAG] if the process name cannot be read.
+    ///
+    /// NOTE: The kernel warns about every allocation without a tag.
+    ///
+    /// [NvMap::DEFAULT_TAG]: struct.NvMap.html#associatedconstant.DEFAULT_TAG
+    pub fn default_tag() -> u16 {
+        static TAG: OnceLock<u16> = OnceLock::new();
+
+        *TAG.get_or_init(|| match std::fs::read_to_string("/proc/self/comm") {
+            Ok(name) => tag_from_name(name.trim_end()),
+            Err(_) => Self::DEFAULT_TAG as u16,
+        })
+    }
+
     /// Create a new instance of NvMap by opening `/dev/nvmap`.
     pub fn new() -> std::io::Result<Self> {
         let file = OpenOptions::new()
@@ -239,41 +585,97 @@ impl NvMap {
     }
 
     /// Creates a new memory handle from a given size.
-    pub fn create(&self, size: u32) -> NvMapResult<Handle> {
-        let mut param = CreateHandle { size, handle: 0 };
+    ///
+    /// NOTE: The kernel interface is limited to 32 bits sizes, ``EINVAL`` is returned above ``u32::MAX``.
+    pub fn create(&self, size: u64) -> NvMapResult<Handle> {
+        let mut param = CreateHandle {
+            size: u32::try_from(size).map_err(|_| Errno::EINVAL)?,
+            handle: 0,
+        };
 
         let res = unsafe { ioc_create(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let fd = self.get_fd(param.handle)?;
+        map_ioctl_result(res)?;
 
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(Handle::from_raw(param.handle, fd, size))
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        let fd = self.get_fd(param.handle)?;
+
+        Handle::new_owned(self, param.handle, fd, size, true)
     }
 
     /// Creates a new memory handle by using another memory handle file descriptor.
     ///
     /// NOTE: The memory handle returned by this method will be referencing to the given file descriptor.
-    pub fn create_from_fd(&self, fd: RawFd, size: u32) -> NvMapResult<Handle> {
+    pub fn create_from_fd(&self, fd: RawFd, size: u64) -> NvMapResult<Handle> {
         let mut param = CreateHandleFromFd { fd, handle: 0 };
 
         let res = unsafe { ioc_from_fd(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(Handle::from_raw(param.handle, fd, size))
-            } else {
-                Err(Errno::from_i32(errno))
-            }
+        map_ioctl_result(res)?;
+
+        Handle::new_owned(self, param.handle, fd, size, false)
+    }
+
+    /// Creates a new memory handle backed by an existing region of the application address space.
+    ///
+    /// This allows the GPU to access a CPU allocated buffer without any copy.
+    ///
+    /// Returns ``ENOTTY`` if the kernel doesn't support ``NVMAP_IOC_FROM_VA``.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `size` must be aligned to the page size ([PAGE_SIZE]) and describe a region mapped in the application address space.
+    /// The region is pinned by the kernel but must not be unmapped or freed by the application before the returned handle is freed.
+    ///
+    /// [PAGE_SIZE]: constant.PAGE_SIZE.html
+    pub unsafe fn create_from_va(&self, ptr: *mut u8, size: u64) -> NvMapResult<Handle> {
+        if ptr.is_null()
+            || (ptr as usize & (PAGE_SIZE - 1)) != 0
+            || (size & (PAGE_SIZE as u64 - 1)) != 0
+        {
+            return Err(Errno::EINVAL);
         }
+
+        let mut param = CreateHandleFromVa {
+            va: ptr as u64,
+            size: u32::try_from(size).map_err(|_| Errno::EINVAL)?,
+            flags: AllocationFlags::HANDLE_CACHEABLE
+                .with_tag(Self::default_tag())
+                .bits(),
+            handle: 0,
+        };
+
+        map_ioctl_result(ioc_from_va(self.file.as_raw_fd(), &mut param))?;
+
+        let fd = self.get_fd(param.handle)?;
+
+        Handle::new_owned(self, param.handle, fd, size, true)
+    }
+
+    /// Get the global id of a memory handle, allowing to share it with unrelated processes.
+    pub fn get_id(&self, handle: &Handle) -> NvMapResult<u32> {
+        let mut param = HandleId {
+            id: 0,
+            handle: handle.raw_handle,
+        };
+
+        let res = unsafe { ioc_get_id(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Ok(param.id)
+    }
+
+    /// Creates a new memory handle referencing the memory handle with the given global id.
+    ///
+    /// NOTE: Unlike [NvMap::create], no file descriptor is received (``fd`` is -1) and none is closed on drop,
+    /// use [NvMap::get_fd] to get one (owned by the caller).
+    ///
+    /// [NvMap::create]: struct.NvMap.html#method.create
+    /// [NvMap::get_fd]: struct.NvMap.html#method.get_fd
+    pub fn from_id(&self, id: u32, size: u64) -> NvMapResult<Handle> {
+        let mut param = HandleId { id, handle: 0 };
+
+        let res = unsafe { ioc_from_id(self.file.as_raw_fd(), &mut param) };
+        map_ioctl_result(res)?;
+
+        Handle::new_owned(self, param.handle, -1, size, false)
     }
 
     /// Retrieve the file descriptor backing a memory handle.
@@ -281,19 +683,17 @@ impl NvMap {
         let mut param = HandleGetFd { fd: 0, handle };
 
         let res = unsafe { ioc_get_fd(self.file.as_raw_fd(), &mut param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(param.fd)
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        map_ioctl_result(res)?;
+
+        Ok(param.fd)
     }
 
     /// Allocate GPU memory to the given memory handle.
+    ///
+    /// The allocation tag can be given with [AllocationFlags::with_tag], [NvMap::default_tag] is used otherwise.
+    ///
+    /// [AllocationFlags::with_tag]: struct.AllocationFlags.html#method.with_tag
+    /// [NvMap::default_tag]: struct.NvMap.html#method.default_tag
     pub fn allocate(
         &self,
         handle: &Handle,
@@ -304,115 +704,488 @@ impl NvMap {
         let param = AllocateHandle {
             handle: handle.raw_handle,
             heap_mask: heap_mask.bits(),
-            flags: flags.bits() | (Self::DEFAULT_TAG << 16),
+            flags: Self::tagged_flags(flags).bits(),
             align,
         };
 
         let res = unsafe { ioc_allocate(self.file.as_raw_fd(), &param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
+        map_ioctl_result(res)
+    }
+
+    /// Allocate GPU memory of the given memory kind to the given memory handle (block linear surfaces for example).
+    ///
+    /// The kind must match the one the memory is mapped with in the GPU address space, see [NvMap::allocate] for the allocation tag.
+    ///
+    /// [NvMap::allocate]: struct.NvMap.html#method.allocate
+    pub fn allocate_with_kind(
+        &self,
+        handle: &Handle,
+        heap_mask: HeapMask,
+        flags: AllocationFlags,
+        align: u32,
+        kind: u8,
+    ) -> NvMapResult<()> {
+        let param = AllocateKindHandle {
+            handle: handle.raw_handle,
+            heap_mask: heap_mask.bits(),
+            flags: Self::tagged_flags(flags).bits(),
+            align,
+            kind,
+            comp_tags: 0,
+        };
+
+        let res = unsafe { ioc_allocate_kind(self.file.as_raw_fd(), &param) };
+        map_ioctl_result(res)
+    }
+
+    /// Add the default allocation tag to the given flags if they don't have one.
+    fn tagged_flags(flags: AllocationFlags) -> AllocationFlags {
+        if flags.tag().is_some() {
+            flags
         } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
+            flags.with_tag(Self::default_tag())
+        }
+    }
+
+    /// Create a memory handle of ``size`` bytes and allocate its memory, trying each heap in order until one succeeds.
+    ///
+    /// Returns the memory handle with the heap that was used or the error of the last attempt if all failed.
+    ///
+    /// NOTE: a fresh memory handle is created for each attempt, the one of a failed attempt is freed.
+    /// This is why this takes a size and returns the handle instead of allocating to a given one like [NvMap::allocate]:
+    /// a failed ``NVMAP_IOC_ALLOC`` may leave the handle partially set up, so it can't be safely reused for the next heap.
+    ///
+    /// [NvMap::allocate]: struct.NvMap.html#method.allocate
+    pub fn allocate_with_fallback(
+        &self,
+        size: u64,
+        heaps: &[HeapMask],
+        flags: AllocationFlags,
+        align: u32,
+    ) -> NvMapResult<(Handle, HeapMask)> {
+        let mut last_error = Errno::EINVAL;
+
+        for heap in heaps {
+            let handle = self.create(size)?;
+
+            match self.allocate(&handle, *heap, flags, align) {
+                Ok(()) => return Ok((handle, *heap)),
+                Err(errno) => last_error = errno,
             }
         }
+
+        Err(last_error)
+    }
+
+    /// Create a memory handle, allocate its memory and map it to the application address space.
+    ///
+    /// NOTE: On failure, the partially initialized memory handle is freed.
+    pub fn alloc_mapped(
+        &self,
+        size: u64,
+        heap_mask: HeapMask,
+        flags: AllocationFlags,
+        align: u32,
+    ) -> NvMapResult<Handle> {
+        // The handle is freed on drop if any step fails.
+        let mut handle = self.create(size)?;
+
+        self.allocate(&handle, heap_mask, flags, align)?;
+        self.map(&mut handle)?;
+
+        Ok(handle)
     }
 
     /// Map the GPU memory backing the given memory handle to the application address space.
     pub fn map(&self, handle: &mut Handle) -> NvMapResult<()> {
-        if handle.addr().is_some() {
+        self.map_with_prot(handle, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE)
+    }
+
+    /// Map the GPU memory backing the given memory handle with the given protection (read only for example).
+    ///
+    /// Returns ``EEXIST`` if the memory handle is already mapped with a different protection or window.
+    pub fn map_with_prot(&self, handle: &mut Handle, prot: ProtFlags) -> NvMapResult<()> {
+        let size = handle.size() as usize;
+
+        self.map_range_with_prot(handle, 0, size, prot)
+    }
+
+    /// Map a window of the GPU memory backing the given memory handle to the application address space.
+    ///
+    /// ``offset`` must be aligned to the page size ([PAGE_SIZE]), ``EINVAL`` is returned if the window
+    /// doesn't fit in the memory handle.
+    ///
+    /// NOTE: Nothing is done if the same window is already mapped with the same protection,
+    /// ``EEXIST`` is returned if another window or protection is mapped.
+    ///
+    /// [PAGE_SIZE]: constant.PAGE_SIZE.html
+    pub fn map_range(&self, handle: &mut Handle, offset: u64, length: usize) -> NvMapResult<()> {
+        self.map_range_with_prot(
+            handle,
+            offset,
+            length,
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+        )
+    }
+
+    fn map_range_with_prot(
+        &self,
+        handle: &mut Handle,
+        offset: u64,
+        length: usize,
+        prot: ProtFlags,
+    ) -> NvMapResult<()> {
+        if handle.is_mapped_as(offset, length, prot)? {
             return Ok(());
         }
 
+        let fits = matches!(
+            offset.checked_add(length as u64),
+            Some(end) if end <= handle.size()
+        );
+
+        if length == 0 || (offset & (PAGE_SIZE as u64 - 1)) != 0 || !fits {
+            return Err(Errno::EINVAL);
+        }
+
         let mmap_res = unsafe {
             mmap(
                 std::ptr::null_mut(),
-                handle.size() as usize,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                length,
+                prot,
                 MapFlags::MAP_SHARED,
                 handle.fd,
-                0,
+                offset as i64,
             )
         }?;
 
         handle.mapped_address = Some(mmap_res as *mut u8);
+        handle.mapped_offset = offset;
+        handle.mapped_length = length;
+        handle.mapped_prot = prot;
         Ok(())
     }
 
     /// Unmap the backed GPU memory of a given memory handle from the application address space.
     pub fn unmap(&self, handle: &mut Handle) -> NvMapResult<()> {
         if let Some(addr) = handle.addr() {
-            unsafe { munmap(addr as *mut _, handle.size as usize) }?;
+            unsafe { munmap(addr as *mut _, handle.mapped_length) }?;
 
             handle.mapped_address = None;
+            handle.mapped_offset = 0;
+            handle.mapped_length = 0;
+            handle.mapped_prot = ProtFlags::PROT_NONE;
         }
         Ok(())
     }
 
     /// Operate cache maintenance of the backed memory of a given memory handle.
-    fn cache_maintenance(
+    ///
+    /// Returns ``EFAULT`` if the memory handle isn't mapped (nothing was done) and ``EINVAL`` if the range
+    /// isn't covered by the mapping.
+    pub fn cache_maintenance(
         &self,
         handle: &Handle,
         offset: u32,
         size: u32,
-        operation: i32,
+        operation: CacheOp,
     ) -> NvMapResult<()> {
         if handle.addr().is_none() {
-            return Ok(());
+            return Err(Errno::EFAULT);
         }
 
-        let mapped_address = handle.addr().unwrap();
+        match handle.mapped_range(offset, size) {
+            Some(address) => self.cache_maintenance_at(handle, address, size, operation),
+            None => Err(Errno::EINVAL),
+        }
+    }
+
+    /// Operate cache maintenance of the backed memory of a given memory handle mapped at the given address.
+    fn cache_maintenance_at(
+        &self,
+        handle: &Handle,
+        address: *mut u8,
+        size: u32,
+        operation: CacheOp,
+    ) -> NvMapResult<()> {
         let param = HandleCacheMaintenance {
-            address: mapped_address as u64 + u64::from(offset),
+            address: address as u64,
             handle: handle.raw_handle,
             length: size,
-            operation,
+            operation: i32::from(operation),
         };
 
         let res = unsafe { ioc_cache(self.file.as_raw_fd(), &param) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
+        map_ioctl_result(res)
+    }
+
+    /// Copy the given data to the memory handle at the given offset and flush it.
+    ///
+    /// The memory handle is mapped if needed and stays mapped.
+    ///
+    /// Returns ``EINVAL`` if the data doesn't fit in the memory handle.
+    pub fn write(&self, handle: &mut Handle, offset: u32, data: &[u8]) -> NvMapResult<()> {
+        let size = checked_range_size(handle, offset, data.len())?;
+
+        self.map(handle)?;
+
+        // A partial mapping may not cover the range.
+        let address = handle.mapped_range(offset, size).ok_or(Errno::EINVAL)?;
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), address, data.len());
+        }
+
+        self.writeback(handle, offset, size)
+    }
+
+    /// Invalidate the memory handle at the given offset and copy its content to the given buffer.
+    ///
+    /// If the memory handle isn't mapped, a temporary mapping is used.
+    ///
+    /// Returns ``EINVAL`` if the buffer doesn't fit in the memory handle.
+    pub fn read(&self, handle: &Handle, offset: u32, out: &mut [u8]) -> NvMapResult<()> {
+        let size = checked_range_size(handle, offset, out.len())?;
+
+        let (address, temporary_mapping) = match handle.mapped_range(offset, size) {
+            Some(address) => (address, None),
+            None => {
+                let mmap_res = unsafe {
+                    mmap(
+                        std::ptr::null_mut(),
+                        handle.size() as usize,
+                        ProtFlags::PROT_READ,
+                        MapFlags::MAP_SHARED,
+                        handle.fd,
+                        0,
+                    )
+                }?;
+
+                let mapped_address = mmap_res as *mut u8;
+
+                (
+                    unsafe { mapped_address.add(offset as usize) },
+                    Some(mapped_address),
+                )
             }
+        };
+
+        let res = self.cache_maintenance_at(handle, address, size, CacheOp::Invalidate);
+
+        if res.is_ok() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(address, out.as_mut_ptr(), out.len());
+            }
+        }
+
+        if let Some(mapped_address) = temporary_mapping {
+            unsafe { munmap(mapped_address as *mut _, handle.size() as usize) }?;
         }
+
+        res
     }
 
     /// Flush the cache of the backed memory of a given memory handle.
     pub fn writeback(&self, handle: &Handle, offset: u32, size: u32) -> NvMapResult<()> {
-        self.cache_maintenance(handle, offset, size, CACHE_OPERATION_WRITE_BACK)
+        self.cache_maintenance(handle, offset, size, CacheOp::WriteBack)
     }
 
     /// Invalidate the cache of the backed memory of a given memory handle.
     pub fn invalidate(&self, handle: &Handle, offset: u32, size: u32) -> NvMapResult<()> {
-        self.cache_maintenance(handle, offset, size, CACHE_OPERATION_INVALIDATE)
+        self.cache_maintenance(handle, offset, size, CacheOp::Invalidate)
     }
 
     /// Flush and invalidate the cache of the backed memory of a given memory handle.
     pub fn writeback_invalidate(&self, handle: &Handle, offset: u32, size: u32) -> NvMapResult<()> {
-        self.cache_maintenance(handle, offset, size, CACHE_OPERATION_WRITE_BACK_INVALIDATE)
+        self.cache_maintenance(handle, offset, size, CacheOp::WriteBackInvalidate)
     }
 
     #[allow(clippy::cast_possible_wrap)]
     /// Free the memory handle and it's backed memory.
-    pub fn free(&self, handle: Handle) -> NvMapResult<()> {
+    ///
+    /// NOTE: Handles are also freed on drop, this allows to get the error of the operation.
+    pub fn free(&self, mut handle: Handle) -> NvMapResult<()> {
         let res = unsafe { ioc_free(self.file.as_raw_fd(), handle.raw_handle as i32) };
-        if res.is_err() {
-            Err(Errno::UnknownErrno)
-        } else {
-            let errno = res.unwrap();
-            if errno == 0 {
-                Ok(())
-            } else {
-                Err(Errno::from_i32(errno))
-            }
-        }
+        map_ioctl_result(res)?;
+
+        // Only the mapping and the file descriptor are left to release on drop.
+        handle.freed = true;
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for NvMap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for NvMap {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check if the given address is part of a mapping of this process.
+    fn is_mapped(address: usize) -> bool {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+
+        maps.lines().any(|line| {
+            let range = line.split_whitespace().next().unwrap();
+            let (start, end) = range.split_once('-').unwrap();
+
+            let start = usize::from_str_radix(start, 16).unwrap();
+            let end = usize::from_str_radix(end, 16).unwrap();
+
+            (start..end).contains(&address)
+        })
+    }
+
+    #[test]
+    fn heap_mask_iterates_in_fallback_order() {
+        let mask = HeapMask::CARVEOUT_GENERIC | HeapMask::IOVMM | HeapMask::CARVEOUT_VPR;
+
+        assert_eq!(
+            mask.iter().collect::<Vec<_>>(),
+            [
+                HeapMask::IOVMM,
+                HeapMask::CARVEOUT_VPR,
+                HeapMask::CARVEOUT_GENERIC
+            ]
+        );
+        assert_eq!(HeapMask::empty().iter().count(), 0);
+        assert_eq!(
+            HeapMask::all().iter().collect::<Vec<_>>(),
+            HeapMask::fallback_order()
+        );
+    }
+
+    #[test]
+    fn tagged_allocation_flags_word() {
+        let flags = AllocationFlags::upload().with_tag(0xCAFE);
+
+        assert_eq!(flags.bits(), 0xCAFE_0001);
+        assert_eq!(flags.tag(), Some(0xCAFE));
+
+        // Retagging replaces the previous tag and keeps the cache flags.
+        let flags = flags.with_tag(0x1234);
+        assert_eq!(flags.bits(), 0x1234_0001);
+        assert_eq!(AllocationFlags::readback().tag(), None);
+
+        assert_eq!(tag_from_name("nvapp"), 0xE0B6);
+    }
+
+    #[test]
+    fn mapping_another_window_is_rejected() {
+        let mut handle = Handle::from_raw(1, -1, 0x3000);
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+
+        assert_eq!(handle.is_mapped_as(0, 0x3000, prot), Ok(false));
+
+        // Pretend the second page is mapped, the address is never accessed.
+        handle.mapped_address = Some(std::ptr::NonNull::dangling().as_ptr());
+        handle.mapped_offset = 0x1000;
+        handle.mapped_length = 0x1000;
+        handle.mapped_prot = prot;
+
+        assert_eq!(handle.is_mapped_as(0x1000, 0x1000, prot), Ok(true));
+        assert_eq!(handle.is_mapped_as(0, 0x1000, prot), Err(Errno::EEXIST));
+        assert_eq!(
+            handle.is_mapped_as(0x1000, 0x2000, prot),
+            Err(Errno::EEXIST)
+        );
+        assert_eq!(
+            handle.is_mapped_as(0x1000, 0x1000, ProtFlags::PROT_READ),
+            Err(Errno::EEXIST)
+        );
+
+        // Not actually mapped, don't munmap on drop.
+        handle.mapped_address = None;
+    }
+
+    #[test]
+    fn allocate_kind_matches_the_kernel_layout() {
+        // Two trailing bytes of padding after the kind and compression tags count.
+        assert_eq!(std::mem::size_of::<AllocateKindHandle>(), 20);
+    }
+
+    #[test]
+    fn partial_ranges_are_bounds_checked() {
+        let handle = Handle::from_raw(1, -1, 0x3000);
+
+        assert_eq!(checked_range_size(&handle, 0x1000, 0x1000), Ok(0x1000));
+        assert_eq!(checked_range_size(&handle, 0x2FFF, 1), Ok(1));
+        assert_eq!(checked_range_size(&handle, 0x3000, 0), Ok(0));
+        assert_eq!(
+            checked_range_size(&handle, 0x2000, 0x1001),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(checked_range_size(&handle, u32::MAX, 2), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    #[ignore = "requires an nvmap device"]
+    fn flush_and_invalidate_partial_ranges() {
+        let nvmap = NvMap::new().unwrap();
+        let handle = nvmap
+            .alloc_mapped(
+                3 * PAGE_SIZE as u64,
+                HeapMask::IOVMM,
+                AllocationFlags::upload(),
+                PAGE_SIZE as u32,
+            )
+            .unwrap();
+        let address = handle.addr().unwrap();
+        let offset = PAGE_SIZE as u32 + 0x40;
+
+        unsafe { address.add(offset as usize).write_volatile(0xA5) };
+
+        handle.flush_range(&nvmap, offset, 0x40).unwrap();
+        handle.invalidate_range(&nvmap, offset, 0x40).unwrap();
+        handle
+            .flush_range(&nvmap, 2 * PAGE_SIZE as u32, PAGE_SIZE as u32)
+            .unwrap();
+
+        assert_eq!(
+            unsafe { address.add(offset as usize).read_volatile() },
+            0xA5
+        );
+
+        assert_eq!(
+            handle.flush_range(&nvmap, 2 * PAGE_SIZE as u32, PAGE_SIZE as u32 + 1),
+            Err(Errno::EINVAL)
+        );
+        assert_eq!(
+            handle.invalidate_range(&nvmap, u32::MAX, 1),
+            Err(Errno::EINVAL)
+        );
+    }
+
+    #[test]
+    #[ignore = "requires an nvmap device"]
+    fn dropping_a_mapped_handle_unmaps_it() {
+        let nvmap = NvMap::new().unwrap();
+        let handle = nvmap
+            .alloc_mapped(
+                PAGE_SIZE as u64,
+                HeapMask::IOVMM,
+                AllocationFlags::upload(),
+                PAGE_SIZE as u32,
+            )
+            .unwrap();
+
+        let address = handle.addr().unwrap() as usize;
+        assert!(is_mapped(address));
+
+        // The handle holds its own reference to the NvMap instance.
+        drop(nvmap);
+        drop(handle);
+
+        assert!(!is_mapped(address));
     }
 }